@@ -0,0 +1,1070 @@
+//! Turns assembly source into machine code
+
+use crate::data::Directive;
+use crate::diag::{Diagnostic, Diagnostics, Span};
+use crate::expr;
+use crate::instr::{Instruction, MathFunc, OpCode, RegImm, Word, LINK_REGISTER};
+use crate::lexer::{self, Line, Token};
+use crate::macros;
+use crate::mem::Endian;
+use crate::object::{RelocKind, Relocation};
+use crate::pseudo::Pseudo;
+use crate::reg;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::{fs, path::Path};
+
+use anyhow::{bail, Result};
+
+/// Assembled machine code
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub words: Vec<u32>,
+    /// Every label and its address
+    pub symbols: BTreeMap<String, u32>,
+    /// The source line (counting from 0) each word came from
+    pub source_map: Vec<usize>,
+    /// Byte addresses of the words from data directives, rather than instructions
+    pub data: BTreeSet<u32>,
+    /// Labels exported with `.globl`, for linking
+    pub exports: BTreeSet<String>,
+    /// Everywhere an address is used, for linking
+    pub relocations: Vec<Relocation>,
+}
+
+impl Program {
+    /// Write the words in the format the simulators read: one `{:08x}` word per line
+    pub fn write_hex(&self, mut output: impl Write) -> io::Result<()> {
+        for word in &self.words {
+            writeln!(output, "{:08x}", word)?;
+        }
+        Ok(())
+    }
+
+    /// Write a listing of `source`, which this was assembled from: each line with the address, bits and disassembly
+    /// of its words, then every label sorted by name. A scheduled program's words can be out of order with the
+    /// source, so they're listed with their line wherever they ended up
+    pub fn write_listing(&self, source: &str, mut output: impl Write) -> io::Result<()> {
+        const BLANK: &str = "";
+        let mut lines: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, &line) in self.source_map.iter().enumerate() {
+            lines.entry(line).or_default().push(i);
+        }
+        for (line_num, text) in source.lines().enumerate() {
+            let mut first = true;
+            for i in lines.remove(&line_num).unwrap_or_default() {
+                let word = self.words[i];
+                let text = if first { text } else { BLANK };
+                let listed = format!(
+                    "{:08x}  {:08x}  {:<20}  {}",
+                    4 * i,
+                    word,
+                    Word::from(word).to_string(),
+                    text
+                );
+                writeln!(output, "{}", listed.trim_end())?;
+                first = false;
+            }
+            if first {
+                let listed = format!("{:8}  {:8}  {:20}  {}", BLANK, BLANK, BLANK, text);
+                writeln!(output, "{}", listed.trim_end())?;
+            }
+        }
+
+        writeln!(output, "\nSymbols:")?;
+        for (name, addr) in &self.symbols {
+            writeln!(output, "{:08x}  {}", addr, name)?;
+        }
+        Ok(())
+    }
+
+    /// The source line (counting from 0) of the word at byte address `addr`
+    pub fn line_of(&self, addr: u32) -> Option<usize> {
+        self.source_map.get(addr as usize / 4).copied()
+    }
+}
+
+/// Assemble `source`, or return every error found in it. `.include`s are relative to the current directory
+pub fn assemble(source: &str) -> Result<Program, Diagnostics> {
+    assemble_in(source, Path::new(""), false, Endian::default())
+}
+
+/// Assemble `source` into an object for [`crate::object::link`], which can use `.extern`s and addresses in any way
+/// that can be relocated
+pub fn assemble_object(source: &str) -> Result<Program, Diagnostics> {
+    assemble_in(source, Path::new(""), true, Endian::default())
+}
+
+/// Assemble `source`, with `.include`s relative to `dir` and strings packed `endian`
+pub(crate) fn assemble_in(
+    source: &str,
+    dir: &Path,
+    object: bool,
+    endian: Endian,
+) -> Result<Program, Diagnostics> {
+    let mut diags = Diagnostics::default();
+    let mut include =
+        |path: &str| fs::read_to_string(dir.join(path)).map_err(|err| err.to_string());
+    let expanded = macros::expand(source, &mut include, &mut diags);
+    let source = &expanded.text();
+    // Diagnostics about the expanded source, moved to the original source at the end
+    let mut expanded_diags = Diagnostics::default();
+
+    // First Pass
+    let (symbols, sizes) = get_symbols(source, &mut expanded_diags);
+    let mut program = Program {
+        symbols: symbols
+            .iter()
+            .filter_map(|(&name, &(symbol, _))| match symbol {
+                Symbol::Label(addr) => Some((name.to_owned(), addr)),
+                Symbol::Equ(_) | Symbol::Extern => None,
+            })
+            .collect(),
+        ..Program::default()
+    };
+    write_instructions(
+        source,
+        &mut program,
+        &symbols,
+        &sizes,
+        object,
+        endian,
+        &mut expanded_diags,
+    );
+    for line in &mut program.source_map {
+        *line = expanded.source_line(*line);
+    }
+    diags.extend(expanded_diags.into_iter().map(|diag| expanded.locate(diag)));
+
+    if diags.has_errors() {
+        Err(diags)
+    } else {
+        Ok(program)
+    }
+}
+
+/// Read and assemble the file at `path`. If it has errors, they're printed to stderr
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Program> {
+    assemble_path(path.as_ref(), false, Endian::default())
+}
+
+/// Read and assemble the file at `path` into an object. If it has errors, they're printed to stderr
+pub fn assemble_object_file(path: impl AsRef<Path>) -> Result<Program> {
+    assemble_path(path.as_ref(), true, Endian::default())
+}
+
+/// Read and assemble the file at `path`, into an object if `object`, with strings packed for an `endian` memory. If
+/// it has errors, they're printed to stderr
+pub fn assemble_path(path: &Path, object: bool, endian: Endian) -> Result<Program> {
+    let source = fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    match assemble_in(&source, dir, object, endian) {
+        Ok(program) => Ok(program),
+        Err(diags) => {
+            eprint!("{}", diags.render(&path.display().to_string(), &source));
+            let count = diags.errors().count();
+            bail!(
+                "could not assemble {} due to {} previous error{}",
+                path.display(),
+                count,
+                if count == 1 { "" } else { "s" }
+            );
+        }
+    }
+}
+
+/// What a name in an expression refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Symbol<'a> {
+    /// A label's address
+    Label(u32),
+    /// The expression of a `.equ`, evaluated wherever it's used
+    Equ(Token<'a>),
+    /// A label in another object, from `.extern`
+    Extern,
+}
+
+/// Every label and `.equ` by name, with where it was defined
+pub(crate) type Symbols<'a> = HashMap<&'a str, (Symbol<'a>, Span)>;
+
+/// Find every symbol, and the size in words of each line.
+/// Sizes can depend on symbols, so they're worked out with only the symbols defined before each line
+fn get_symbols<'a>(input: &'a str, diags: &mut Diagnostics) -> (Symbols<'a>, Vec<u32>) {
+    let mut symbols = Symbols::new();
+    let mut sizes = Vec::new();
+    let mut addr = 0;
+    for line in lexer::lex(input) {
+        let mut define = |name: Token<'a>, symbol| {
+            if let Some(&(_, first)) = symbols.get(name.text) {
+                diags.push(
+                    Diagnostic::error(
+                        name.span,
+                        format!("`{}` is defined more than once", name.text),
+                    )
+                    .with_note(format!("first defined on line {}", first.line + 1)),
+                );
+            } else {
+                symbols.insert(name.text, (symbol, name.span));
+            }
+        };
+        if let Some(label) = line.label {
+            define(label, Symbol::Label(addr));
+        }
+        if let (Some(".equ"), Some(&name), Some(&value)) = (
+            line.op.map(|op| op.text),
+            line.operands.first(),
+            line.operands.get(1),
+        ) {
+            define(name, Symbol::Equ(value));
+        }
+        if let (Some(".extern"), Some(&name)) = (line.op.map(|op| op.text), line.operands.first()) {
+            define(name, Symbol::Extern);
+        }
+        // A label on a line of its own is the address of the next word
+        let size = size(&line, addr, &symbols);
+        sizes.push(size);
+        // Directives that would go past the end of memory take no words, and are reported when they're emitted
+        addr = addr.saturating_add(4 * size);
+    }
+    (symbols, sizes)
+}
+
+/// Alias name to register, from `.alias name reg`
+pub(crate) type Aliases<'a> = HashMap<&'a str, u8>;
+
+/// How many words the line at byte address `addr` assembles to
+fn size(line: &Line, addr: u32, symbols: &Symbols) -> u32 {
+    let op = match line.op {
+        Some(op) => op.text,
+        None => return 0,
+    };
+    if [".alias", ".equ", ".globl", ".extern"].contains(&op) {
+        0
+    } else if let Ok(pseudo) = op.parse::<Pseudo>() {
+        pseudo.size(&line.operands, symbols)
+    } else if let Ok(directive) = op.parse::<Directive>() {
+        directive.size(&line.operands, addr, symbols)
+    } else {
+        1
+    }
+}
+
+/// The operands following a mnemonic. Anything after the last operand is a comment
+pub(crate) struct Operands<'a, 'b, I> {
+    toks: I,
+    /// Where a missing operand is reported
+    end: Span,
+    op: &'a str,
+    aliases: &'b Aliases<'a>,
+    /// Addresses used by the line's words
+    relocs: &'b mut Vec<Pending<'a>>,
+}
+
+/// An address used by a word of a line, which has to be relocated
+pub(crate) struct Pending<'a> {
+    /// Which of the line's words
+    word: u32,
+    kind: RelocKind,
+    reloc: Reloc<'a>,
+    tok: Token<'a>,
+}
+
+impl<'a, I: Iterator<Item = Token<'a>>> Operands<'a, '_, I> {
+    pub(crate) fn next(&mut self, diags: &mut Diagnostics, what: &str) -> Option<Token<'a>> {
+        let tok = self.toks.next();
+        if tok.is_none() {
+            diags.error(
+                self.end,
+                format!("missing operand: `{}` expects {} here", self.op, what),
+            );
+        }
+        tok
+    }
+
+    pub(crate) fn reg(&mut self, diags: &mut Diagnostics) -> Option<u8> {
+        let tok = self.next(diags, "a register")?;
+        parse_reg(tok, self.aliases, diags)
+    }
+
+    /// Record that the line's word number `word` uses the address `reloc`
+    pub(crate) fn relocate(
+        &mut self,
+        word: u32,
+        kind: RelocKind,
+        tok: Token<'a>,
+        reloc: Reloc<'a>,
+    ) {
+        if reloc != Reloc::None {
+            self.relocs.push(Pending {
+                word,
+                kind,
+                reloc,
+                tok,
+            });
+        }
+    }
+
+    /// An expression that fits in a 16 bit signed immediate, in the line's word number `word`
+    pub(crate) fn imm(
+        &mut self,
+        imm: Token<'a>,
+        word: u32,
+        symbols: &Symbols<'a>,
+        diags: &mut Diagnostics,
+    ) -> Option<i16> {
+        let value = eval_or_report(imm, symbols, diags)?;
+        self.relocate(word, RelocKind::Abs16, imm, value.reloc);
+        check_imm(imm, value, diags)
+    }
+
+    /// An expression that fits in a 16 bit unsigned immediate, in the line's word number `word`. The immediate is
+    /// zero extended, so addresses in it can't be relocated like the ones in [`Self::imm`]
+    pub(crate) fn uimm(
+        &mut self,
+        imm: Token<'a>,
+        word: u32,
+        symbols: &Symbols<'a>,
+        diags: &mut Diagnostics,
+    ) -> Option<u16> {
+        let value = eval_or_report(imm, symbols, diags)?;
+        if value.reloc != Reloc::None {
+            self.relocate(word, RelocKind::Abs16, imm, Reloc::Invalid);
+        }
+        match u16::try_from(value.num) {
+            Ok(num) => Some(num),
+            Err(_) => {
+                diags.push(
+                    Diagnostic::error(
+                        imm.span,
+                        format!("immediate `{}` is out of range", imm.text),
+                    )
+                    .with_note(format!(
+                        "{} doesn't fit in 16 unsigned bits (0..={})",
+                        value.num,
+                        u16::MAX
+                    )),
+                );
+                None
+            }
+        }
+    }
+
+    /// The byte address a `j` or `jal` at `pc` goes to, the line's word number `word`. It has to be a word in the
+    /// same 256MB as the jump
+    pub(crate) fn jump(
+        &mut self,
+        target: Token<'a>,
+        pc: u32,
+        word: u32,
+        symbols: &Symbols<'a>,
+        diags: &mut Diagnostics,
+    ) -> Option<u32> {
+        let value = eval_or_report(target, symbols, diags)?;
+        self.relocate(word, RelocKind::Abs26, target, value.reloc);
+        let region = i64::from(pc + 4) & !0x0fff_ffff;
+        let note = if value.num % 4 != 0 {
+            format!("{} isn't a multiple of 4", value.num)
+        } else if value.num & !0x0fff_ffff != region {
+            format!(
+                "{} isn't in the same 256MB as the jump ({:#x}..={:#x})",
+                value.num,
+                region,
+                region + 0x0fff_ffff
+            )
+        } else {
+            return Some(value.num as u32);
+        };
+        diags.push(
+            Diagnostic::error(
+                target.span,
+                format!("jump target `{}` is out of range", target.text),
+            )
+            .with_note(note),
+        );
+        None
+    }
+
+    /// A constant shift amount, 0..=31
+    pub(crate) fn shamt(&mut self, symbols: &Symbols<'a>, diags: &mut Diagnostics) -> Option<u8> {
+        let tok = self.next(diags, "a shift amount")?;
+        let value = eval_or_report(tok, symbols, diags)?;
+        match u8::try_from(value.num) {
+            Ok(num) if num < 32 && !value.label => Some(num),
+            _ => {
+                let note = if value.label {
+                    "shift amounts can't be addresses".to_owned()
+                } else {
+                    format!("{} isn't in 0..=31", value.num)
+                };
+                diags.push(
+                    Diagnostic::error(
+                        tok.span,
+                        format!("shift amount `{}` is out of range", tok.text),
+                    )
+                    .with_note(note),
+                );
+                None
+            }
+        }
+    }
+
+    /// A constant offset, or a label's address which is turned into an offset from the instruction after `pc`.
+    /// The branch is the line's word number `word`
+    pub(crate) fn branch(
+        &mut self,
+        imm: Token<'a>,
+        pc: u32,
+        word: u32,
+        symbols: &Symbols<'a>,
+        diags: &mut Diagnostics,
+    ) -> Option<i16> {
+        let value = eval_or_report(imm, symbols, diags)?;
+        if !value.label {
+            return check_imm(imm, value, diags);
+        }
+        // Moving the whole object doesn't change offsets within it
+        if value.reloc != Reloc::Base {
+            self.relocate(word, RelocKind::Rel16, imm, value.reloc);
+        }
+        let offset = value.num - i64::from(pc) - 4;
+        match i16::try_from(offset) {
+            Ok(offset) => Some(offset),
+            Err(_) => {
+                diags.push(
+                    Diagnostic::error(
+                        imm.span,
+                        format!("branch target `{}` is too far away", imm.text),
+                    )
+                    .with_note(format!(
+                        "the offset {} doesn't fit in 16 signed bits",
+                        offset
+                    )),
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Assemble every line. `object` is whether addresses have to be relocatable, for linking
+fn write_instructions<'a>(
+    input: &'a str,
+    output: &mut Program,
+    symbols: &Symbols<'a>,
+    sizes: &[u32],
+    object: bool,
+    endian: Endian,
+    diags: &mut Diagnostics,
+) {
+    let mut pc = 0;
+    let mut aliases = Aliases::new();
+    for (line_num, (text, line)) in input.lines().zip(lexer::lex(input)).enumerate() {
+        let op = match line.op {
+            Some(op) => op,
+            None => continue,
+        };
+        let mut relocs = Vec::new();
+        let mut toks = Operands {
+            toks: line.operands.iter().copied(),
+            end: Span::new(line_num, text.trim_end().len(), 1),
+            op: op.text,
+            aliases: &aliases,
+            relocs: &mut relocs,
+        };
+
+        let size = sizes[line_num];
+        let words = if op.text == ".equ" {
+            // Defined in the first pass, but the value is only checked here
+            let name = toks.next(diags, "a name");
+            if let Some(value) = toks.next(diags, "a constant expression") {
+                if let Err(err) = eval_defining(value, symbols, name.map(|name| name.text)) {
+                    diags.push(err);
+                }
+            }
+            Some(Vec::new())
+        } else if op.text == ".alias" {
+            // Aliases take effect from the line they're defined on, and can be redefined
+            let name = toks.next(diags, "a name");
+            let target = toks.reg(diags);
+            if let Some((name, target)) = name.zip(target) {
+                if reg::parse(name.text).is_some() {
+                    diags.error(name.span, format!("`{}` is already a register", name.text));
+                } else {
+                    aliases.insert(name.text, target);
+                }
+            }
+            Some(Vec::new())
+        } else if op.text == ".globl" {
+            if let Some(name) = toks.next(diags, "a label") {
+                match symbols.get(name.text) {
+                    Some((Symbol::Label(_), _)) => {
+                        output.exports.insert(name.text.to_owned());
+                    }
+                    _ => diags.error(
+                        name.span,
+                        format!("`{}` has to be a label to be exported", name.text),
+                    ),
+                }
+            }
+            Some(Vec::new())
+        } else if op.text == ".extern" {
+            // Defined in the first pass
+            toks.next(diags, "a name");
+            Some(Vec::new())
+        } else if let Ok(pseudo) = op.text.parse::<Pseudo>() {
+            pseudo
+                .expand(&mut toks, pc, symbols, diags)
+                .map(|instrs| instrs.into_iter().map(Instruction::as_u32).collect())
+        } else if let Ok(directive) = op.text.parse::<Directive>() {
+            directive.emit(&mut toks, pc, symbols, endian, diags)
+        } else if let Ok(func) = op.text.parse::<MathFunc>() {
+            let a0 = toks.reg(diags);
+            // `mfhi rd` and `mflo rd` only have rd
+            let a1 = if func.reads_hi_lo() {
+                Some(0)
+            } else {
+                toks.reg(diags)
+            };
+            let instr = if func.reads_hi_lo() {
+                a0.map(|rd| Instruction::math(func, (rd, 0, 0)))
+            } else if func.writes_hi_lo() {
+                // `mult rs rt`, which doesn't write rd
+                a0.zip(a1)
+                    .map(|(rs, rt)| Instruction::math(func, (0, rs, rt)))
+            } else if func.uses_shamt() {
+                let shamt = toks.shamt(symbols, diags);
+                a0.zip(a1)
+                    .zip(shamt)
+                    .map(|((a0, a1), shamt)| Instruction::shift(func, (a0, a1, shamt)))
+            } else {
+                let a2 = toks.reg(diags);
+                a0.zip(a1)
+                    .zip(a2)
+                    .map(|((a0, a1), a2)| Instruction::math(func, (a0, a1, a2)))
+            };
+            match instr.map(Instruction::as_u32) {
+                // It would decode as data
+                Some(0) => {
+                    diags.push(
+                        Diagnostic::error(
+                            op.span,
+                            "`sll 0 0 0` is the all zero word, which is data",
+                        )
+                        .with_note("use `nop` for an instruction that does nothing"),
+                    );
+                    None
+                }
+                word => word.map(|word| vec![word]),
+            }
+        } else if let Ok(regimm) = op.text.parse::<RegImm>() {
+            let rs = toks.reg(diags);
+            let imm = toks
+                .next(diags, "an immediate or label")
+                .and_then(|imm| toks.branch(imm, pc, 0, symbols, diags));
+            rs.zip(imm)
+                .map(|(rs, imm)| vec![Instruction::regimm(regimm, rs, imm).as_u32()])
+        } else if let Ok(opcode) = op.text.parse::<OpCode>() {
+            let instr = match opcode {
+                OpCode::ADDI
+                | OpCode::SLTI
+                | OpCode::LW
+                | OpCode::SW
+                | OpCode::LB
+                | OpCode::LBU
+                | OpCode::LH
+                | OpCode::LHU
+                | OpCode::SB
+                | OpCode::SH => {
+                    let a0 = toks.reg(diags);
+                    let a1 = toks.reg(diags);
+                    let imm = toks
+                        .next(diags, "an immediate or label")
+                        .and_then(|imm| toks.imm(imm, 0, symbols, diags));
+
+                    a0.zip(a1)
+                        .zip(imm)
+                        .map(|((a0, a1), imm)| Instruction::i_type(opcode, (a0, a1, imm)))
+                }
+                OpCode::BEQ | OpCode::BNE => {
+                    let a0 = toks.reg(diags);
+                    let a1 = toks.reg(diags);
+                    let imm = toks
+                        .next(diags, "an immediate or label")
+                        .and_then(|imm| toks.branch(imm, pc, 0, symbols, diags));
+                    // `beqz rt rs imm` is the original syntax, `beq rs rt imm` is MIPS's
+                    let (rs, rt) = if op.text == "beqz" {
+                        (a1, a0)
+                    } else {
+                        (a0, a1)
+                    };
+                    rs.zip(rt)
+                        .zip(imm)
+                        .map(|((rs, rt), imm)| Instruction::i_type(opcode, (rt, rs, imm)))
+                }
+                OpCode::J | OpCode::JAL => toks
+                    .next(diags, "a label or address")
+                    .and_then(|target| toks.jump(target, pc, 0, symbols, diags))
+                    .map(|target| Instruction::jump(opcode == OpCode::JAL, target)),
+                OpCode::ANDI | OpCode::ORI | OpCode::XORI => {
+                    let a0 = toks.reg(diags);
+                    let a1 = toks.reg(diags);
+                    let imm = toks
+                        .next(diags, "an immediate")
+                        .and_then(|imm| toks.uimm(imm, 0, symbols, diags));
+                    a0.zip(a1)
+                        .zip(imm)
+                        .map(|((a0, a1), imm)| Instruction::i_type(opcode, (a0, a1, imm as i16)))
+                }
+                OpCode::LUI => {
+                    let a0 = toks.reg(diags);
+                    let imm = toks
+                        .next(diags, "an immediate")
+                        .and_then(|imm| toks.uimm(imm, 0, symbols, diags));
+                    a0.zip(imm).map(|(a0, imm)| Instruction::lui(a0, imm))
+                }
+                OpCode::JALR => {
+                    // Either `jalr rd rs`, or `jalr rs` which links into LINK_REGISTER
+                    let first = toks.next(diags, "a register");
+                    let a0 = first.and_then(|a0| parse_reg(a0, &aliases, diags));
+                    let is_alias = |name: &str| aliases.contains_key(name);
+                    match toks
+                        .toks
+                        .next()
+                        .filter(|&a1| first.is_some_and(|a0| jalr_second(a0, a1, is_alias)))
+                    {
+                        Some(a1) => {
+                            let a1 = parse_reg(a1, &aliases, diags);
+                            a0.zip(a1).map(|(a0, a1)| Instruction::jalr(a0, a1))
+                        }
+                        None => a0.map(|a0| Instruction::jalr(LINK_REGISTER, a0)),
+                    }
+                }
+                OpCode::HALT => Some(Instruction::halt()),
+                OpCode::SYSCALL => Some(Instruction::Syscall),
+                OpCode::BREAK => Some(Instruction::Break),
+                // `mfc0 rt rd`, where rd is the number of a coprocessor 0 register
+                OpCode::MFC0 => {
+                    let rt = toks.reg(diags);
+                    let rd = toks.reg(diags);
+                    rt.zip(rd).map(|(rt, rd)| Instruction::Mfc0 { rt, rd })
+                }
+                // These are only opcodes, the instructions are named by their func or rt
+                OpCode::MATH | OpCode::REGIMM => {
+                    diags.error(op.span, format!("unknown mnemonic `{}`", op.text));
+                    None
+                }
+            };
+            instr.map(|instr| vec![instr.as_u32()])
+        } else {
+            let mut diag = Diagnostic::error(op.span, format!("unknown mnemonic `{}`", op.text));
+            if let Some(label) = line.label.filter(|l| is_mnemonic(l.text)) {
+                diag = diag.with_note(format!(
+                    "`{}` is in the label column, instructions must be indented",
+                    label.text
+                ));
+            }
+            diags.push(diag);
+            None
+        };
+
+        let words = match words {
+            Some(words) if words.len() != size as usize => {
+                diags.push(
+                    Diagnostic::error(
+                        op.span,
+                        format!("the size of `{}` depends on a later definition", op.text),
+                    )
+                    .with_note("labels and `.equ`s used here have to be defined before this line"),
+                );
+                None
+            }
+            words => words,
+        };
+        if words.is_some() {
+            for pending in relocs {
+                let symbol = match pending.reloc {
+                    Reloc::Base => None,
+                    Reloc::Extern(name) if object => Some(name.to_owned()),
+                    Reloc::Extern(name) => {
+                        diags.push(
+                            Diagnostic::error(
+                                pending.tok.span,
+                                format!("`{}` is an `.extern`, so it has to be linked", name),
+                            )
+                            .with_note("assemble with `-c` to make an object, then `link` it"),
+                        );
+                        continue;
+                    }
+                    Reloc::Invalid if object => {
+                        diags.push(
+                            Diagnostic::error(
+                                pending.tok.span,
+                                format!("`{}` can't be relocated", pending.tok.text),
+                            )
+                            .with_note("in an object, addresses can only be used as `label` or `label+constant`"),
+                        );
+                        continue;
+                    }
+                    // A program that isn't linked is never moved
+                    Reloc::Invalid | Reloc::None => continue,
+                };
+                output.relocations.push(Relocation {
+                    addr: pc + 4 * pending.word,
+                    kind: pending.kind,
+                    symbol,
+                });
+            }
+        }
+        // Keep the addresses right even if there were errors, so later labels are still checked properly
+        let words = words.unwrap_or_else(|| vec![0; size as usize]);
+        let data = op.text.parse::<Directive>().is_ok();
+        for word in words {
+            if data {
+                output.data.insert(pc);
+            }
+            output.words.push(word);
+            output.source_map.push(line_num);
+            pc += 4;
+        }
+    }
+}
+
+pub(crate) fn is_mnemonic(text: &str) -> bool {
+    text.parse::<MathFunc>().is_ok()
+        || text.parse::<RegImm>().is_ok()
+        || text.parse::<OpCode>().is_ok()
+        || text.parse::<Pseudo>().is_ok()
+        || text.parse::<Directive>().is_ok()
+        || text == ".alias"
+        || text == ".equ"
+        || [
+            ".macro", ".endm", ".rept", ".endr", ".include", ".globl", ".extern",
+        ]
+        .contains(&text)
+}
+
+/// Whether `second`, the field after `jalr`'s `first` operand, is its rs rather than a comment
+pub(crate) fn jalr_second(first: Token, second: Token, is_alias: impl Fn(&str) -> bool) -> bool {
+    // Anything written like a register counts, even an out of range number, so that it's an error
+    first.comma
+        || second.text.parse::<i64>().is_ok()
+        || reg::parse(second.text).is_some()
+        || is_alias(second.text)
+}
+
+/// A register number, name or alias
+fn parse_reg(reg: Token, aliases: &Aliases, diags: &mut Diagnostics) -> Option<u8> {
+    if let Some(num) = reg::parse(reg.text).or_else(|| aliases.get(reg.text).copied()) {
+        return Some(num);
+    }
+    let digits = reg.text.trim_start_matches('$').trim_start_matches('r');
+    if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        diags.error(
+            reg.span,
+            format!("register `{}` is out of range (0..31)", reg.text),
+        );
+    } else {
+        diags.push(
+            Diagnostic::error(
+                reg.span,
+                format!("expected a register, found `{}`", reg.text),
+            )
+            .with_note("registers are written `5`, `r5`, `$5`, `$r5`, `$t0`, or an `.alias`"),
+        );
+    }
+    None
+}
+
+/// The value of an expression, whether it came from a label's address, and how it's relocated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Value<'a> {
+    pub num: i64,
+    pub label: bool,
+    pub reloc: Reloc<'a>,
+}
+
+/// What has to be added to a value when its object is linked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Reloc<'a> {
+    /// Nothing, it's a constant. Differences of labels, like `end-start`, are constants too
+    None,
+    /// The object's address, for a value that moves with its labels
+    Base,
+    /// The address of an `.extern`
+    Extern(&'a str),
+    /// It depends on addresses in a way that can't be fixed by adding one, like `label*2`
+    Invalid,
+}
+
+/// How far addresses are moved to find out how an expression depends on them
+const RELOC_SHIFT: i64 = 1 << 24;
+
+/// Evaluate the expression in `tok`
+pub(crate) fn eval<'a>(tok: Token, symbols: &Symbols<'a>) -> Result<Value<'a>, Diagnostic> {
+    eval_defining(tok, symbols, None)
+}
+
+/// Evaluate the expression in `tok`, which is the value of the `.equ` named `defining`
+fn eval_defining<'a>(
+    tok: Token,
+    symbols: &Symbols<'a>,
+    defining: Option<&str>,
+) -> Result<Value<'a>, Diagnostic> {
+    /// `stack` has the `.equ`s being evaluated, to catch cycles. Every address used is added to `used`, and the one
+    /// that's `shifted` is moved by `RELOC_SHIFT`
+    fn eval_text<'a>(
+        text: &str,
+        symbols: &Symbols<'a>,
+        stack: &mut Vec<&'a str>,
+        used: &mut Vec<Reloc<'a>>,
+        shifted: Reloc<'a>,
+    ) -> Result<i64, expr::ExprError> {
+        fn address<'a>(
+            reloc: Reloc<'a>,
+            addr: u32,
+            used: &mut Vec<Reloc<'a>>,
+            shifted: Reloc<'a>,
+        ) -> i64 {
+            if !used.contains(&reloc) {
+                used.push(reloc);
+            }
+            let addr = i64::from(addr);
+            if reloc == shifted {
+                addr + RELOC_SHIFT
+            } else {
+                addr
+            }
+        }
+
+        expr::eval(text, &mut |name| match symbols.get_key_value(name) {
+            Some((_, &(Symbol::Label(addr), _))) => Ok(address(Reloc::Base, addr, used, shifted)),
+            // Its address is only known once it's linked, so it's added then
+            Some((&name, &(Symbol::Extern, _))) => {
+                Ok(address(Reloc::Extern(name), 0, used, shifted))
+            }
+            Some(_) if stack.contains(&name) => {
+                Err(format!("`{}` is defined in terms of itself", name))
+            }
+            Some((&name, &(Symbol::Equ(value), _))) => {
+                stack.push(name);
+                let result = eval_text(value.text, symbols, stack, used, shifted);
+                stack.pop();
+                result.map_err(|err| {
+                    // Only say which `.equ` failed once, for the outermost one
+                    if stack.is_empty() {
+                        format!("`{}` can't be evaluated: {}", name, err)
+                    } else {
+                        err.message
+                    }
+                })
+            }
+            None => Err(format!("undefined label `{}`", name)),
+        })
+    }
+
+    let mut used = Vec::new();
+    let mut stack: Vec<&str> = symbols
+        .get_key_value(defining.unwrap_or_default())
+        .map(|(&name, _)| name)
+        .into_iter()
+        .collect();
+    let num = eval_text(tok.text, symbols, &mut stack, &mut used, Reloc::None).map_err(|err| {
+        Diagnostic::error(
+            Span::new(tok.span.line, tok.span.col + err.col, err.len.max(1)),
+            err.message,
+        )
+    })?;
+
+    // Move each address it uses in turn. If the value only moves with one of them, and by the same amount, that's
+    // what it's relocated by
+    let mut reloc = Reloc::None;
+    for &base in &used {
+        let moved = eval_text(tok.text, symbols, &mut stack, &mut Vec::new(), base)
+            .ok()
+            .and_then(|moved| moved.checked_sub(num));
+        match moved {
+            Some(0) => {}
+            Some(RELOC_SHIFT) if reloc == Reloc::None => reloc = base,
+            _ => {
+                reloc = Reloc::Invalid;
+                break;
+            }
+        }
+    }
+    Ok(Value {
+        num,
+        label: !used.is_empty(),
+        reloc,
+    })
+}
+
+/// Evaluate `tok`, reporting any errors
+pub(crate) fn eval_or_report<'a>(
+    tok: Token,
+    symbols: &Symbols<'a>,
+    diags: &mut Diagnostics,
+) -> Option<Value<'a>> {
+    eval(tok, symbols).map_err(|err| diags.push(err)).ok()
+}
+
+/// Check `value`, of the expression `imm`, fits in a 16 bit signed immediate
+fn check_imm(imm: Token, value: Value, diags: &mut Diagnostics) -> Option<i16> {
+    match i16::try_from(value.num) {
+        Ok(num) => Some(num),
+        Err(_) => {
+            let what = if value.label {
+                "label address"
+            } else {
+                "immediate"
+            };
+            diags.push(
+                Diagnostic::error(imm.span, format!("{} `{}` is out of range", what, imm.text))
+                    .with_note(format!(
+                        "{} doesn't fit in 16 signed bits ({}..={})",
+                        value.num,
+                        i16::MIN,
+                        i16::MAX
+                    )),
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let program =
+            assemble("\tlw\t1\t0\tfive\n\n# comment\nloop\tbeqz\t0\t0\tloop\nfive\t.fill\t5\n")
+                .unwrap();
+        assert_eq!(program.words, [0x8c01_0008, 0x1000_fffc, 5]);
+        assert_eq!(program.symbols["loop"], 4);
+        assert_eq!(program.symbols["five"], 8);
+        assert_eq!(program.source_map, [0, 3, 4]);
+        assert_eq!(program.line_of(4), Some(3));
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = "# count\n\tli\t2\t0x12345678\nloop\tbeqz\t0\t0\tloop\n";
+        let mut listing = Vec::new();
+        assemble(source)
+            .unwrap()
+            .write_listing(source, &mut listing)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "                                          # count
+00000000  3c021234  lui 2 4660            \tli\t2\t0x12345678
+00000004  34425678  ori 2 2 22136
+00000008  1000fffc  beqz 0 0 -4           loop\tbeqz\t0\t0\tloop
+
+Symbols:
+00000008  loop
+"
+        );
+    }
+
+    #[test]
+    fn test_register_names() {
+        let program =
+            assemble("\t.alias\tcount\t$t0\n\taddi\tcount\t$zero\t5\n\tadd\tr2, $3, count\n")
+                .unwrap();
+        let numbers = assemble("\taddi\t8\t0\t5\n\tadd\t2\t3\t8\n").unwrap();
+        assert_eq!(program.words, numbers.words);
+
+        // `jalr rd rs` without a comma, however rs is written. Anything else after rd is a comment
+        let program = assemble(
+            "\t.alias\ttarget\t5\n\tjalr\tr31 r5\n\tjalr\t$ra $a1\n\tjalr\t31\ttarget\n\tjalr\t5\tcall it\n",
+        )
+        .unwrap();
+        assert_eq!(program.words[..3], [Instruction::jalr(31, 5).as_u32(); 3]);
+        assert_eq!(
+            program.words[3],
+            Instruction::jalr(LINK_REGISTER, 5).as_u32()
+        );
+
+        let diags = assemble("\t.alias\t$sp\t1\n\tadd\t1\t2\tcount\n").unwrap_err();
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`$sp` is already a register",
+                "expected a register, found `count`"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let program = assemble(
+            "\t.equ\tLEN\t(end-table)/4\n\
+             \taddi\t1\t0\tLEN\n\
+             \tlw\t2\t0\ttable+4\n\
+             \taddi\t3\t0\t%lo(0x12348765)\n\
+             table\t.word\t0x7fff, 0b1010, 'A'\n\
+             end\tbeqz\t0\t0\t(end-4)\n\
+             \tlui\t4\t%hi(0xdeadbeef)\n\
+             \taddi\t4\t4\t%lo(0xdeadbeef)\n",
+        )
+        .unwrap();
+        assert_eq!(
+            program.words,
+            [
+                0x2001_0003,
+                0x8c02_0010,
+                0x2003_8765,
+                0x7fff,
+                10,
+                65,
+                0x1000_fff8,
+                0x3c04_deae,
+                0x2084_beef
+            ]
+        );
+
+        let diags = assemble(
+            "\taddi\t1\t0\t0x8000\n\
+             \t.equ\tA\tB\n\
+             \t.equ\tB\tA+1\n\
+             \t.space\tlater\n\
+             later\tlw\t1\t0\t(2+)\n",
+        )
+        .unwrap_err();
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "immediate `0x8000` is out of range",
+                "`A` is defined in terms of itself",
+                "`B` is defined in terms of itself",
+                "the size of `.space` depends on a later definition",
+                "expected a number or name, found `)`"
+            ]
+        );
+        assert_eq!(diags.iter().last().unwrap().span, Span::new(4, 16, 1));
+    }
+
+    #[test]
+    fn test_errors() {
+        let diags =
+            assemble("\tadd\t1\t2\n\tbeqz\t0\t0\tnowhere\n\tsra\t1\t2\t32\n\tsll\t0\t0\t0\n\tori\t1\t1\t-1\n")
+                .unwrap_err();
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "missing operand: `add` expects a register here",
+                "undefined label `nowhere`",
+                "shift amount `32` is out of range",
+                "`sll 0 0 0` is the all zero word, which is data",
+                "immediate `-1` is out of range",
+            ]
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use assembler::assemble_file;
+use assembler::diag::Severity;
+use assembler::lint::lint;
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Result};
+use argh::FromArgs;
+
+/// Report load-use stalls, writes to r0, unreachable code, and execution reaching data
+#[derive(FromArgs)]
+struct Args {
+    /// the mips assembly files to check
+    #[argh(positional)]
+    inputs: Vec<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let Args { inputs } = argh::from_env::<Args>();
+    let mut errors = 0;
+    for input in &inputs {
+        let source = fs::read_to_string(input)?;
+        let diags = lint(&assemble_file(input)?, &source);
+        if !diags.is_empty() {
+            println!("{}", diags.render(&input.display().to_string(), &source));
+        }
+        errors += diags
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count();
+    }
+    if errors > 0 {
+        bail!(
+            "found {} error{}",
+            errors,
+            if errors == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use assembler::disasm::{disassemble, read_hex};
+
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use argh::FromArgs;
+
+/// Turn machine code back into assembly
+#[derive(FromArgs)]
+struct Args {
+    /// the machine code file, one hex word per line
+    #[argh(option, short = 'i')]
+    input: PathBuf,
+    /// the mips assembly file to write
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let Args { input, output } = argh::from_env::<Args>();
+    let file = File::open(&input).with_context(|| format!("could not open {}", input.display()))?;
+    let words = read_hex(BufReader::new(file))
+        .with_context(|| format!("could not read {}", input.display()))?;
+    fs::write(output, disassemble(&words))?;
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use assembler::assemble_object_file;
+use assembler::format::Format;
+use assembler::object::{link, read_object_file};
+
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+use argh::FromArgs;
+
+/// Link objects made by `assembler -c` into machine code. The first one is where execution starts
+#[derive(FromArgs)]
+struct Args {
+    /// the linked machine code file
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+    /// the machine code format: hex (the default), bin-le, bin-be, ihex, logisim, memh, c or rust
+    #[argh(option, default = "Format::default()")]
+    format: Format,
+    /// object files, or mips assembly files which are assembled into objects first
+    #[argh(positional)]
+    inputs: Vec<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let Args {
+        output,
+        format,
+        inputs,
+    } = argh::from_env::<Args>();
+    let objects = inputs
+        .iter()
+        .map(|input| {
+            let object = if input.extension() == Some("mips".as_ref()) {
+                assemble_object_file(input)?
+            } else {
+                read_object_file(input)?
+            };
+            Ok((input.display().to_string(), object))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let program = link(&objects)?;
+
+    // Only create the output once we know it's complete
+    let mut words = Vec::new();
+    format.write(&program.words, &mut words)?;
+    fs::write(output, words)?;
+    Ok(())
+}
@@ -0,0 +1,20 @@
+use assembler::lsp::run;
+
+use std::io;
+
+use anyhow::Result;
+use argh::FromArgs;
+
+/// A language server for mips assembly, speaking JSON-RPC over stdio
+#[derive(FromArgs)]
+struct Args {}
+
+fn main() -> Result<()> {
+    let Args {} = argh::from_env::<Args>();
+    let stdin = io::stdin();
+    // Exiting without a `shutdown` first is an error
+    if !run(stdin.lock(), io::stdout())? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
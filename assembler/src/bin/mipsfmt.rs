@@ -0,0 +1,42 @@
+use assembler::fmt::format_file;
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Result};
+use argh::FromArgs;
+
+/// Format mips assembly files in place, in the canonical tab separated layout
+#[derive(FromArgs)]
+struct Args {
+    /// don't write anything, just list the files that aren't formatted and fail if there are any
+    #[argh(switch)]
+    check: bool,
+    /// the mips assembly files to format
+    #[argh(positional)]
+    inputs: Vec<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let Args { check, inputs } = argh::from_env::<Args>();
+    let mut unformatted = 0;
+    for input in &inputs {
+        let (source, formatted) = format_file(input)?;
+        if source == formatted {
+            continue;
+        }
+        if check {
+            println!("{} isn't formatted", input.display());
+            unformatted += 1;
+        } else {
+            fs::write(input, formatted)?;
+        }
+    }
+    if unformatted > 0 {
+        bail!(
+            "{} file{} would be reformatted",
+            unformatted,
+            if unformatted == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
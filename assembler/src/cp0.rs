@@ -0,0 +1,125 @@
+//! Exceptions, and the coprocessor 0 registers that describe them
+//!
+//! An instruction that causes an exception doesn't finish, and neither does anything after it. EPC is set to its
+//! address, Cause to the exception's code shifted left 2, like MIPS, and BadVAddr to the address of an address error.
+//! Then execution continues at the handler, or the machine halts if there isn't one. A handler reads the registers
+//! with `mfc0 rt rd`, and can return with `jalr 0 rs` to EPC, or EPC + 4 to skip the instruction.
+
+use crate::instr::{DecodeError, Word};
+use crate::mem::AddressError;
+
+use std::fmt::Display;
+
+/// The coprocessor 0 register numbers `mfc0` reads
+pub const BAD_VADDR: u8 = 8;
+pub const CAUSE: u8 = 13;
+pub const EPC: u8 = 14;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    /// A word that isn't an instruction was executed
+    Illegal(u32, DecodeError),
+    /// A load whose address is unaligned or outside memory
+    AddressLoad(AddressError),
+    /// A store whose address is unaligned or outside memory
+    AddressStore(AddressError),
+    /// Signed overflow in `add`
+    Overflow,
+    Syscall,
+    Break,
+}
+
+impl Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exception::Illegal(..) => write!(f, "illegal instruction"),
+            Exception::AddressLoad(_) | Exception::AddressStore(_) => write!(f, "address error"),
+            Exception::Overflow => write!(f, "arithmetic overflow"),
+            Exception::Syscall => write!(f, "syscall"),
+            Exception::Break => write!(f, "break"),
+        }
+    }
+}
+
+impl Exception {
+    /// The ExcCode field of Cause
+    pub fn code(self) -> u32 {
+        match self {
+            Exception::AddressLoad(_) => 4,
+            Exception::AddressStore(_) => 5,
+            Exception::Syscall => 8,
+            Exception::Break => 9,
+            Exception::Illegal(..) => 10,
+            Exception::Overflow => 12,
+        }
+    }
+
+    /// What the trap message says after the exception, for the `instr` that caused it
+    pub fn details(self, instr: Word) -> String {
+        match self {
+            Exception::Illegal(bits, err) => format!("{:08x} ({})", bits, err),
+            Exception::AddressLoad(err) | Exception::AddressStore(err) => {
+                format!("{} ({})", err, instr)
+            }
+            _ => instr.to_string(),
+        }
+    }
+}
+
+/// The registers exceptions set
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cp0 {
+    pub epc: u32,
+    pub cause: u32,
+    pub bad_vaddr: u32,
+    /// Where exceptions go, or `None` to halt on them
+    pub handler: Option<u32>,
+    /// How many exceptions have been taken
+    pub taken: usize,
+}
+
+impl Cp0 {
+    /// Take `exception`, caused by the instruction at `pc`. Gives the handler to continue at
+    pub fn take(&mut self, exception: Exception, pc: u32) -> Option<u32> {
+        self.epc = pc;
+        self.cause = exception.code() << 2;
+        if let Exception::AddressLoad(err) | Exception::AddressStore(err) = exception {
+            self.bad_vaddr = err.addr();
+        }
+        self.taken += 1;
+        self.handler
+    }
+
+    /// Register `reg`, for `mfc0`. The ones exceptions don't set are 0
+    pub fn read(&self, reg: u8) -> u32 {
+        match reg {
+            BAD_VADDR => self.bad_vaddr,
+            CAUSE => self.cause,
+            EPC => self.epc,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let mut cp0 = Cp0::default();
+        let err = AddressError::Unaligned(0x29);
+        assert_eq!(cp0.take(Exception::AddressStore(err), 32), None);
+        assert_eq!(
+            [cp0.read(EPC), cp0.read(CAUSE), cp0.read(BAD_VADDR)],
+            [32, 5 << 2, 0x29]
+        );
+
+        cp0.handler = Some(100);
+        assert_eq!(cp0.take(Exception::Overflow, 8), Some(100));
+        assert_eq!([cp0.read(EPC), cp0.read(CAUSE)], [8, 12 << 2]);
+        // BadVAddr is left alone
+        assert_eq!(cp0.read(BAD_VADDR), 0x29);
+        assert_eq!(cp0.taken, 2);
+    }
+}
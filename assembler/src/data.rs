@@ -0,0 +1,367 @@
+//! Directives that put data in memory
+//!
+//! * `.fill n` and `.word a, b, c` are 32 bit expressions, signed or unsigned. Like instructions, anything after the
+//!   last operand is a comment, so `.word` only keeps taking values while they're separated by commas
+//! * `.space n` is `n` zeroed bytes, rounded up to whole words
+//! * `.ascii "str"` and `.asciiz "str"` (with a terminating 0) are packed 4 bytes a word, and padded with zeros.
+//!   They're packed little endian unless assembled for a big endian memory, so `lb` reads them in order either way. The escapes `\n`, `\t`, `\r`, `\0`, `\\`, `\"` and `\'` are supported
+//! * `.align n` pads with zeros up to a multiple of `2^n` bytes. Everything is already word aligned, so only
+//!   `n > 2` does anything
+//! * `.org addr` pads with zeros up to `addr`, which can't be behind the current address
+//!
+//! A program can't grow past [`MAX_BYTES`], so a huge `.space` or `.org` is an error rather than gigabytes of zeros.
+//!
+//! Sizes are needed to find label addresses, so the operands of `.space`, `.align` and `.org` can only use labels and
+//! `.equ`s defined before them.
+
+use crate::asm::{eval, eval_or_report, Operands, Reloc, Symbols};
+use crate::diag::{Diagnostic, Diagnostics};
+use crate::lexer::Token;
+use crate::mem::Endian;
+use crate::object::RelocKind;
+
+use std::convert::TryFrom;
+
+use strum_macros::{Display, EnumString};
+
+#[derive(EnumString, Display, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Directive {
+    #[strum(serialize = ".fill")]
+    Fill,
+    #[strum(serialize = ".word")]
+    Word,
+    #[strum(serialize = ".space")]
+    Space,
+    #[strum(serialize = ".ascii")]
+    Ascii,
+    #[strum(serialize = ".asciiz")]
+    Asciiz,
+    #[strum(serialize = ".align")]
+    Align,
+    #[strum(serialize = ".org")]
+    Org,
+}
+
+/// `.align` above this would pad with more words than fit in memory
+const MAX_ALIGN: u32 = 16;
+
+/// The most bytes a program can take, 16MB
+pub const MAX_BYTES: u32 = 1 << 24;
+
+/// The address after `bytes` more bytes at `addr`, if that's still within [`MAX_BYTES`]
+fn end(addr: u32, bytes: u32) -> Option<u32> {
+    addr.checked_add(bytes).filter(|&end| end <= MAX_BYTES)
+}
+
+impl Directive {
+    /// How many words the directive at byte address `addr` takes. Errors are reported by `emit`, and take no words
+    pub(crate) fn size(self, operands: &[Token], addr: u32, symbols: &Symbols) -> u32 {
+        let first = operands.first();
+        let count = || {
+            first
+                .and_then(|&tok| eval(tok, symbols).ok())
+                .and_then(|value| u32::try_from(value.num).ok())
+        };
+        match self {
+            Directive::Fill => 1,
+            Directive::Word => values(operands).len() as u32,
+            Directive::Space => count()
+                .filter(|&bytes| end(addr, bytes).is_some())
+                .map_or(0, |bytes| bytes.div_ceil(4)),
+            Directive::Ascii | Directive::Asciiz => {
+                first.and_then(|s| unquote(s.text).ok()).map_or(0, |bytes| {
+                    pack(&bytes, self == Directive::Asciiz, Endian::default()).len() as u32
+                })
+            }
+            Directive::Align => count()
+                .filter(|&n| n <= MAX_ALIGN)
+                .map_or(0, |n| (align_up(addr, n) - addr) / 4),
+            Directive::Org => count()
+                .filter(|&org| org >= addr && org % 4 == 0 && org <= MAX_BYTES)
+                .map_or(0, |org| (org - addr) / 4),
+        }
+    }
+
+    /// The words for the directive at byte address `addr`, with strings packed `endian`
+    pub(crate) fn emit<'a>(
+        self,
+        toks: &mut Operands<'a, '_, impl Iterator<Item = Token<'a>>>,
+        addr: u32,
+        symbols: &Symbols<'a>,
+        endian: Endian,
+        diags: &mut Diagnostics,
+    ) -> Option<Vec<u32>> {
+        match self {
+            Directive::Fill | Directive::Word => {
+                let mut words = Vec::new();
+                let mut failed = false;
+                // Comments can follow the last value, so keep going only after a comma
+                loop {
+                    let tok = toks.next(diags, "a 32 bit integer or label")?;
+                    match parse_word(tok, symbols, diags) {
+                        Some((word, reloc)) => {
+                            toks.relocate(words.len() as u32, RelocKind::Abs32, tok, reloc);
+                            words.push(word);
+                        }
+                        None => failed = true,
+                    }
+                    if self == Directive::Fill || !tok.comma {
+                        break;
+                    }
+                }
+                if failed {
+                    None
+                } else {
+                    Some(words)
+                }
+            }
+            Directive::Space => {
+                let tok = toks.next(diags, "a number of bytes")?;
+                let bytes = parse_count(tok, symbols, diags)?;
+                if end(addr, bytes).is_none() {
+                    diags.push(
+                        Diagnostic::error(
+                            tok.span,
+                            format!("`.space {}` doesn't fit in memory", tok.text),
+                        )
+                        .with_note(format!(
+                            "it starts at {}, and programs can be at most {} bytes",
+                            addr, MAX_BYTES
+                        )),
+                    );
+                    return None;
+                }
+                Some(vec![0; bytes.div_ceil(4) as usize])
+            }
+            Directive::Ascii | Directive::Asciiz => {
+                let tok = toks.next(diags, "a quoted string")?;
+                match unquote(tok.text) {
+                    Ok(bytes) => Some(pack(&bytes, self == Directive::Asciiz, endian)),
+                    Err(err) => {
+                        diags.error(tok.span, err);
+                        None
+                    }
+                }
+            }
+            Directive::Align => {
+                let tok = toks.next(diags, "a power of 2")?;
+                let n = parse_count(tok, symbols, diags)?;
+                if n > MAX_ALIGN {
+                    diags.push(
+                        Diagnostic::error(tok.span, format!("can't align to 2^{} bytes", n))
+                            .with_note(format!("the most is `.align {}`", MAX_ALIGN)),
+                    );
+                    return None;
+                }
+                Some(vec![0; ((align_up(addr, n) - addr) / 4) as usize])
+            }
+            Directive::Org => {
+                let tok = toks.next(diags, "an address")?;
+                let org = parse_count(tok, symbols, diags)?;
+                if org % 4 != 0 {
+                    diags.error(tok.span, format!("`.org {}` isn't word aligned", org));
+                    None
+                } else if org < addr {
+                    diags.push(
+                        Diagnostic::error(tok.span, format!("`.org {}` would move backwards", org))
+                            .with_note(format!("the current address is {}", addr)),
+                    );
+                    None
+                } else if org > MAX_BYTES {
+                    diags.push(
+                        Diagnostic::error(
+                            tok.span,
+                            format!("`.org {}` is past the end of memory", org),
+                        )
+                        .with_note(format!("programs can be at most {} bytes", MAX_BYTES)),
+                    );
+                    None
+                } else {
+                    Some(vec![0; ((org - addr) / 4) as usize])
+                }
+            }
+        }
+    }
+}
+
+/// The values of a `.word`: the first field, and every one after a comma
+fn values<'t, 'a>(operands: &'t [Token<'a>]) -> &'t [Token<'a>] {
+    let end = operands
+        .iter()
+        .position(|tok| !tok.comma)
+        .map_or(operands.len(), |last| last + 1);
+    &operands[..end]
+}
+
+/// Round `addr` up to a multiple of `2^n`
+fn align_up(addr: u32, n: u32) -> u32 {
+    let align = 1u32 << n.max(2);
+    (addr + align - 1) & !(align - 1)
+}
+
+/// A 32 bit expression, signed or unsigned, and how it's relocated
+fn parse_word<'a>(
+    tok: Token,
+    symbols: &Symbols<'a>,
+    diags: &mut Diagnostics,
+) -> Option<(u32, Reloc<'a>)> {
+    let value = eval_or_report(tok, symbols, diags)?;
+    let num = value.num;
+    match (i32::try_from(num), u32::try_from(num)) {
+        (Ok(num), _) => Some((num as u32, value.reloc)),
+        (_, Ok(num)) => Some((num, value.reloc)),
+        _ => {
+            diags.push(
+                Diagnostic::error(tok.span, format!("`{}` doesn't fit in 32 bits", tok.text))
+                    .with_note(format!("{} is outside of {}..={}", num, i32::MIN, u32::MAX)),
+            );
+            None
+        }
+    }
+}
+
+/// A size or address
+fn parse_count(tok: Token, symbols: &Symbols, diags: &mut Diagnostics) -> Option<u32> {
+    let num = eval_or_report(tok, symbols, diags)?.num;
+    match u32::try_from(num) {
+        Ok(num) => Some(num),
+        Err(_) => {
+            diags.error(
+                tok.span,
+                format!(
+                    "expected a non-negative 32 bit number, `{}` is {}",
+                    tok.text, num
+                ),
+            );
+            None
+        }
+    }
+}
+
+/// The bytes of a quoted string, with escapes replaced
+fn unquote(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .filter(|_| text.len() >= 2)
+        .ok_or_else(|| format!("expected a quoted string, found `{}`", text))?;
+    let mut bytes = Vec::with_capacity(inner.len());
+    let mut chars = inner.bytes();
+    while let Some(c) = chars.next() {
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'0') => 0,
+            Some(c @ b'\\') | Some(c @ b'"') | Some(c @ b'\'') => c,
+            Some(c) => return Err(format!("unknown escape `\\{}`", c as char)),
+            None => return Err("the string ends with a `\\`".to_owned()),
+        });
+    }
+    Ok(bytes)
+}
+
+/// Pack bytes into `endian` words, with a terminating 0 if `zero`
+fn pack(bytes: &[u8], zero: bool, endian: Endian) -> Vec<u32> {
+    let mut bytes = bytes.to_vec();
+    if zero {
+        bytes.push(0);
+    }
+    bytes
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            match endian {
+                Endian::Little => u32::from_le_bytes(word),
+                Endian::Big => u32::from_be_bytes(word),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble_in;
+    use crate::assemble;
+    use crate::mem::{self, Endian, Width};
+
+    use std::path::Path;
+
+    #[test]
+    fn test_data() {
+        let program = assemble(
+            "\tlw\t1\t0\tend\n\
+             nums\t.word\t1, -1, nums\tcomment\n\
+             \t.space\t5\n\
+             str\t.asciiz\t\"hi\\n there\"\n\
+             \t.align\t4\n\
+             aligned\t.org\t48\n\
+             end\t.fill\t7\n",
+        )
+        .unwrap();
+        assert_eq!(
+            program.words,
+            [
+                0x8c01_0030,
+                1,
+                0xffff_ffff,
+                4,
+                0,
+                0,
+                u32::from_le_bytes(*b"hi\n "),
+                u32::from_le_bytes(*b"ther"),
+                u32::from_le_bytes(*b"e\0\0\0"),
+                0,
+                0,
+                0,
+                7
+            ]
+        );
+        assert_eq!(program.symbols["str"], 24);
+        assert_eq!(program.symbols["aligned"], 48);
+        assert_eq!(program.symbols["end"], 48);
+    }
+
+    #[test]
+    fn test_ascii_endian() {
+        // `lb` reads a string in order, whichever way memory is
+        for endian in [Endian::Little, Endian::Big] {
+            let source = "\tlb\t1\t0\tstr\nstr\t.ascii\t\"mips!\"\n";
+            let program = assemble_in(source, Path::new(""), false, endian).unwrap();
+            let bytes: Vec<u32> = (4..9)
+                .map(|addr| mem::load(&program.words, addr, Width::Byte, endian).unwrap())
+                .collect();
+            assert_eq!(bytes, b"mips!".map(u32::from), "{}", endian);
+        }
+    }
+
+    #[test]
+    fn test_data_errors() {
+        // Too big to fit, rather than an overflow or gigabytes of zeros
+        let diags = assemble("\t.org\t0xfffffff0\n\t.word\t1\n\t.space\t0x40000000\n").unwrap_err();
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`.org 4294967280` is past the end of memory",
+                "`.space 0x40000000` doesn't fit in memory"
+            ]
+        );
+
+        let diags = assemble("\t.org\t8\n\t.org\t4\n\t.ascii\t\"\\q\"\n\t.word\t1,\n").unwrap_err();
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`.org 4` would move backwards",
+                "unknown escape `\\q`",
+                "missing operand: `.word` expects a 32 bit integer or label here"
+            ]
+        );
+    }
+}
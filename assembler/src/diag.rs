@@ -0,0 +1,206 @@
+//! Errors (and warnings) about assembly source, printed like rustc prints them:
+//!
+//! ```text
+//! error: unknown mnemonic `ad`
+//!  --> prog.mips:3:2
+//!   |
+//! 3 |     ad  1   2   3
+//!   |     ^^
+//! ```
+
+use std::fmt::{Display, Write};
+
+/// A range of a single line of source. `line` and `col` count from 0, `col` and `len` are in bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, col: usize, len: usize) -> Self {
+        Self { line, col, len }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    /// Extra lines printed after the snippet as `= note: ...`
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(span, message)
+        }
+    }
+
+    pub fn note(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Note,
+            ..Self::error(span, message)
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Render the diagnostic with a snippet of `source`, the contents of the file at `path`
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let Span { line, col, len } = self.span;
+        let text = source.lines().nth(line).unwrap_or("");
+        let gutter = (line + 1).to_string().len();
+
+        // Tabs are expanded so the carets line up no matter how the terminal displays them
+        let expand = |s: &str| s.replace('\t', "    ");
+        let before = text.get(..col).unwrap_or(text);
+        let marked = text.get(col..col + len).unwrap_or("");
+
+        let mut out = String::new();
+        // Writing to a String can't fail
+        let _ = writeln!(out, "{}: {}", self.severity, self.message);
+        let _ = writeln!(
+            out,
+            "{:>w$}--> {}:{}:{}",
+            "",
+            path,
+            line + 1,
+            col + 1,
+            w = gutter
+        );
+        let _ = writeln!(out, "{:>w$} |", "", w = gutter);
+        let _ = writeln!(
+            out,
+            "{}",
+            format!("{} | {}", line + 1, expand(text)).trim_end()
+        );
+        let _ = writeln!(
+            out,
+            "{:>w$} | {}{}",
+            "",
+            " ".repeat(expand(before).len()),
+            "^".repeat(expand(marked).len().max(1)),
+            w = gutter
+        );
+        for note in &self.notes {
+            let _ = writeln!(out, "{:>w$} = note: {}", "", note, w = gutter);
+        }
+        out
+    }
+}
+
+/// Every problem found in a file. Assembly keeps going after an error so they can all be reported at once
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic)
+    }
+
+    pub fn error(&mut self, span: Span, message: impl Into<String>) {
+        self.push(Diagnostic::error(span, message))
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter().filter(|d| d.severity == Severity::Error)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter()
+    }
+
+    /// Render every diagnostic in source order, separated by blank lines
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let mut sorted: Vec<_> = self.0.iter().collect();
+        sorted.sort_by_key(|d| d.span);
+        sorted
+            .iter()
+            .map(|d| d.render(path, source))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Extend<Diagnostic> for Diagnostics {
+    fn extend<T: IntoIterator<Item = Diagnostic>>(&mut self, iter: T) {
+        self.0.extend(iter)
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = std::vec::IntoIter<Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "\tadd\t1\t0\t0\nloop\tad\t1\t2\t3\n";
+        let diag = Diagnostic::error(Span::new(1, 5, 2), "unknown mnemonic `ad`")
+            .with_note("expected an instruction or directive");
+        assert_eq!(
+            diag.render("prog.mips", source),
+            "error: unknown mnemonic `ad`
+ --> prog.mips:2:6
+  |
+2 | loop    ad    1    2    3
+  |         ^^
+  = note: expected an instruction or directive
+"
+        );
+    }
+}
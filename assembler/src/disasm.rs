@@ -0,0 +1,170 @@
+//! Turns machine code back into assembly that assembles to the same words
+
+use crate::instr::{Instruction, OpCode, Word};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::io::BufRead;
+
+use anyhow::{Context, Result};
+
+/// Read a hex file, as written by the assembler
+pub fn read_hex(input: impl BufRead) -> Result<Vec<u32>> {
+    input
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| {
+            let line = line?;
+            u32::from_str_radix(line.trim(), 16)
+                .with_context(|| format!("line {} isn't a hex word: {:?}", line_num + 1, line))
+        })
+        .collect()
+}
+
+/// Disassemble `words` into source in the tab separated format. Branch and jump targets, the targets of `jalr`s,
+/// and memory accessed with an absolute address get labels. Words that aren't instructions become `.fill`s
+pub fn disassemble(words: &[u32]) -> String {
+    let decoded: Vec<Word> = words.iter().map(|&word| Word::from(word)).collect();
+    let end = words.len() as u32 * 4;
+    let (labels, targets) = find_labels(&decoded, end);
+    let label = |addr: i64| {
+        if addr >= 0 && labels.contains(&(addr as u32)) {
+            Some(format!("L{}", addr))
+        } else {
+            None
+        }
+    };
+    // Swap the immediate, the last field, for a label of `addr`
+    let with_label = |instr: Instruction, addr: i64| {
+        let fields = instr.to_string().replace(' ', "\t");
+        match label(addr) {
+            Some(target) => format!("{}{}", fields.trim_end_matches(|c| c != '\t'), target),
+            None => fields,
+        }
+    };
+
+    let mut out = String::new();
+    for (idx, word) in decoded.iter().enumerate() {
+        let pc = idx as i64 * 4;
+        let text = match *word {
+            Word::Data(bits, _) => format!(".fill\t{}", bits as i32),
+            Word::Instr(instr @ Instruction::Branch { .. })
+            | Word::Instr(instr @ Instruction::Jump { .. }) => {
+                with_label(instr, instr.target(pc as u32).unwrap())
+            }
+            Word::Instr(instr @ Instruction::Load { rs: 0, imm, .. })
+            | Word::Instr(instr @ Instruction::Store { rs: 0, imm, .. }) => {
+                with_label(instr, i64::from(imm))
+            }
+            Word::Instr(
+                instr @ Instruction::Imm {
+                    op: OpCode::ADDI,
+                    rs: 0,
+                    imm,
+                    ..
+                },
+            ) if targets.contains(&idx) => with_label(instr, i64::from(imm)),
+            Word::Instr(instr) => instr.to_string().replace(' ', "\t"),
+        };
+        // Writing to a String can't fail
+        let _ = writeln!(out, "{}\t{}", label(pc).unwrap_or_default(), text);
+    }
+    // Something may point just past the last word
+    if let Some(target) = label(i64::from(end)) {
+        let _ = writeln!(out, "{}", target);
+    }
+    out
+}
+
+/// Addresses that deserve a label, and the `addi`s that load a `jalr` target
+fn find_labels(decoded: &[Word], end: u32) -> (BTreeSet<u32>, BTreeSet<usize>) {
+    let in_program = |addr: i64| addr >= 0 && addr <= i64::from(end) && addr % 4 == 0;
+    let mut labels = BTreeSet::new();
+    let mut targets = BTreeSet::new();
+    for (idx, word) in decoded.iter().enumerate() {
+        let pc = idx as i64 * 4;
+        match *word {
+            Word::Instr(instr @ Instruction::Branch { .. })
+            | Word::Instr(instr @ Instruction::Jump { .. }) => {
+                let target = instr.target(pc as u32).unwrap();
+                if in_program(target) {
+                    labels.insert(target as u32);
+                }
+            }
+            Word::Instr(Instruction::Load { rs: 0, imm, .. })
+            | Word::Instr(Instruction::Store { rs: 0, imm, .. })
+                if in_program(i64::from(imm)) =>
+            {
+                labels.insert(imm as u32);
+            }
+            Word::Instr(Instruction::Jalr { rs, .. }) => {
+                // The target is usually loaded just before with `addi rs 0 target`
+                let setter = decoded[..idx]
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .find_map(|(idx, word)| match word {
+                        Word::Instr(instr) if instr.dest() == Some(rs) => Some((idx, *instr)),
+                        _ => None,
+                    });
+                if let Some((
+                    setter,
+                    Instruction::Imm {
+                        op: OpCode::ADDI,
+                        rs: 0,
+                        imm,
+                        ..
+                    },
+                )) = setter
+                {
+                    if in_program(i64::from(imm)) {
+                        labels.insert(imm as u32);
+                        targets.insert(setter);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    (labels, targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, assemble_file};
+
+    use std::fs;
+
+    #[test]
+    fn test_disassemble() {
+        let program = assemble(
+            "\taddi\t3\t0\tsub\n\tjalr\t3\nloop\tbeqz\t0\t0\tloop\nsub\tlw\t1\t0\tnum\n\tjalr\t0\t31\nnum\t.fill\t-7\n",
+        )
+        .unwrap();
+        assert_eq!(
+            disassemble(&program.words),
+            "\taddi\t3\t0\tL12\n\tjalr\t31\t3\nL8\tbeqz\t0\t0\tL8\nL12\tlw\t1\t0\tL20\n\tjalr\t0\t31\nL20\t.fill\t-7\n"
+        );
+    }
+
+    #[test]
+    fn test_round_trip_public_tests() -> Result<()> {
+        let tests = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests");
+        for entry in fs::read_dir(tests)? {
+            let path = entry?.path();
+            if path.extension() != Some("mips".as_ref()) {
+                continue;
+            }
+            let words = assemble_file(&path)?.words;
+            let source = disassemble(&words);
+            let round_trip = assemble(&source)
+                .unwrap_or_else(|diags| {
+                    panic!("{}", diags.render(&path.display().to_string(), &source))
+                })
+                .words;
+            assert_eq!(round_trip, words, "{}", path.display());
+        }
+        Ok(())
+    }
+}
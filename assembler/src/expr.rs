@@ -0,0 +1,343 @@
+//! Constant expressions in operands, like `label+8`, `(end-start)/4` or `%hi(table)`
+//!
+//! * Numbers are decimal, hex (`0x7fff`), binary (`0b1010`) or octal (`0o17`). Characters (`'A'`, `'\n'`) are their
+//!   byte value
+//! * Names are labels or `.equ` constants
+//! * The operators are C's, with the same precedence: unary `-` `~` `+`, then `*` `/` `%`, `+` `-`, `<<` `>>`, `&`,
+//!   `^` and `|`
+//! * `%lo(x)` is the low 16 bits of `x` as a signed number, and `%hi(x)` is the upper 16 bits as an unsigned one, for
+//!   `lui`. It's adjusted so that `(%hi(x) << 16) + %lo(x)` is `x` in 32 bits, since `%lo` is sign extended when it's
+//!   added
+//!
+//! The lexer splits fields on whitespace, so an expression with spaces has to be in parentheses.
+//! Everything is calculated with 64 bit integers, the caller checks the result fits where it's going.
+
+use std::{convert::TryFrom, fmt::Display};
+
+/// Why an expression couldn't be evaluated, and where in its text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    /// Byte offset into the expression's text
+    pub col: usize,
+    pub len: usize,
+}
+
+impl ExprError {
+    fn new(message: impl Into<String>, col: usize, len: usize) -> Self {
+        Self {
+            message: message.into(),
+            col,
+            len,
+        }
+    }
+}
+
+impl Display for ExprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Evaluate `text`. `resolve` gives the value of a name, or an error message
+pub fn eval(
+    text: &str,
+    resolve: &mut dyn FnMut(&str) -> Result<i64, String>,
+) -> Result<i64, ExprError> {
+    let mut parser = Parser {
+        text,
+        pos: 0,
+        resolve,
+    };
+    let value = parser.bitor()?;
+    parser.skip_space();
+    if parser.pos < text.len() {
+        return Err(ExprError::new(
+            format!("unexpected `{}` in expression", &text[parser.pos..]),
+            parser.pos,
+            text.len() - parser.pos,
+        ));
+    }
+    Ok(value)
+}
+
+/// Parse an integer literal or character, without any operators. `None` if `text` isn't one
+pub fn parse_int(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let num = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if let Some(oct) = digits
+        .strip_prefix("0o")
+        .or_else(|| digits.strip_prefix("0O"))
+    {
+        i64::from_str_radix(oct, 8).ok()?
+    } else if digits.starts_with('\'') {
+        match char_literal(digits) {
+            Some((byte, len)) if len == digits.len() => i64::from(byte),
+            _ => return None,
+        }
+    } else if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -num } else { num })
+}
+
+/// `%lo(x)`, the low half of `x` sign extended
+pub fn lo(x: i64) -> i64 {
+    i64::from(x as u16 as i16)
+}
+
+/// `%hi(x)`, the high half of `x` after taking away `%lo(x)`, zero extended
+pub fn hi(x: i64) -> i64 {
+    i64::from((x.wrapping_sub(lo(x)) >> 16) as u16)
+}
+
+/// A character literal at the start of `text`: its byte and length
+fn char_literal(text: &str) -> Option<(u8, usize)> {
+    let bytes = text.as_bytes();
+    let (byte, len) = match bytes.get(1..)? {
+        [b'\\', c, b'\'', ..] => (
+            match c {
+                b'n' => b'\n',
+                b't' => b'\t',
+                b'r' => b'\r',
+                b'0' => 0,
+                b'\\' | b'\'' | b'"' => *c,
+                _ => return None,
+            },
+            4,
+        ),
+        [c, b'\'', ..] if *c != b'\\' && *c != b'\'' => (*c, 3),
+        _ => return None,
+    };
+    Some((byte, len))
+}
+
+struct Parser<'t, 'r> {
+    text: &'t str,
+    pos: usize,
+    resolve: &'r mut dyn FnMut(&str) -> Result<i64, String>,
+}
+
+type Binary = fn(i64, i64) -> Option<i64>;
+
+impl<'t> Parser<'t, '_> {
+    fn skip_space(&mut self) {
+        while self.rest().starts_with(|c: char| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn rest(&self) -> &'t str {
+        &self.text[self.pos..]
+    }
+
+    /// Consume `op` if it's next
+    fn eat(&mut self, op: &str) -> bool {
+        self.skip_space();
+        if self.rest().starts_with(op) {
+            self.pos += op.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parse a left associative chain of the operators in `ops`, whose operands are parsed by `next`
+    fn binary(
+        &mut self,
+        ops: &[(&str, Binary)],
+        next: fn(&mut Self) -> Result<i64, ExprError>,
+    ) -> Result<i64, ExprError> {
+        let mut lhs = next(self)?;
+        'chain: loop {
+            for (op, apply) in ops {
+                let start = self.pos;
+                if self.eat(op) {
+                    let rhs = next(self)?;
+                    lhs = apply(lhs, rhs).ok_or_else(|| {
+                        ExprError::new(
+                            format!("`{}` can't be applied to {} and {}", op, lhs, rhs),
+                            start,
+                            self.pos - start,
+                        )
+                    })?;
+                    continue 'chain;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn bitor(&mut self) -> Result<i64, ExprError> {
+        self.binary(&[("|", |a, b| Some(a | b))], Self::bitxor)
+    }
+
+    fn bitxor(&mut self) -> Result<i64, ExprError> {
+        self.binary(&[("^", |a, b| Some(a ^ b))], Self::bitand)
+    }
+
+    fn bitand(&mut self) -> Result<i64, ExprError> {
+        self.binary(&[("&", |a, b| Some(a & b))], Self::shift)
+    }
+
+    fn shift(&mut self) -> Result<i64, ExprError> {
+        self.binary(
+            &[
+                ("<<", |a, b| {
+                    u32::try_from(b).ok().and_then(|b| a.checked_shl(b))
+                }),
+                (">>", |a, b| {
+                    u32::try_from(b).ok().and_then(|b| a.checked_shr(b))
+                }),
+            ],
+            Self::sum,
+        )
+    }
+
+    fn sum(&mut self) -> Result<i64, ExprError> {
+        self.binary(
+            &[("+", i64::checked_add), ("-", i64::checked_sub)],
+            Self::product,
+        )
+    }
+
+    fn product(&mut self) -> Result<i64, ExprError> {
+        self.binary(
+            &[
+                ("*", i64::checked_mul),
+                ("/", i64::checked_div),
+                ("%", i64::checked_rem),
+            ],
+            Self::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<i64, ExprError> {
+        if self.eat("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("~") {
+            Ok(!self.unary()?)
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, ExprError> {
+        self.skip_space();
+        let start = self.pos;
+        let rest = self.rest();
+
+        for (name, apply) in [("%hi(", hi as fn(i64) -> i64), ("%lo(", lo)] {
+            if rest.starts_with(name) {
+                self.pos += name.len();
+                let value = self.bitor()?;
+                self.close(start)?;
+                return Ok(apply(value));
+            }
+        }
+
+        if self.eat("(") {
+            let value = self.bitor()?;
+            self.close(start)?;
+            return Ok(value);
+        }
+
+        if rest.starts_with('\'') {
+            let (byte, len) = char_literal(rest)
+                .ok_or_else(|| ExprError::new("invalid character literal", start, rest.len()))?;
+            self.pos += len;
+            return Ok(i64::from(byte));
+        }
+
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        self.pos += len;
+        match word.bytes().next() {
+            None => Err(ExprError::new(
+                match rest.chars().next() {
+                    Some(c) => format!("expected a number or name, found `{}`", c),
+                    None => "expected a number or name".to_owned(),
+                },
+                start,
+                rest.chars().next().map_or(1, char::len_utf8),
+            )),
+            Some(b'0'..=b'9') => parse_int(word)
+                .ok_or_else(|| ExprError::new(format!("invalid number `{}`", word), start, len)),
+            Some(_) => (self.resolve)(word).map_err(|message| ExprError::new(message, start, len)),
+        }
+    }
+
+    /// The `)` matching the `(` at `open`
+    fn close(&mut self, open: usize) -> Result<(), ExprError> {
+        if self.eat(")") {
+            Ok(())
+        } else {
+            Err(ExprError::new("unclosed `(`", open, self.pos - open))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(text: &str) -> Result<i64, ExprError> {
+        eval(text, &mut |name| match name {
+            "start" => Ok(8),
+            "end" => Ok(40),
+            _ => Err(format!("undefined label `{}`", name)),
+        })
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(calc("0x7fff"), Ok(0x7fff));
+        assert_eq!(calc("-0b1010"), Ok(-10));
+        assert_eq!(calc("'A'+'\\n'"), Ok(75));
+        assert_eq!(calc("start+8"), Ok(16));
+        assert_eq!(calc("(end - start)/4"), Ok(8));
+        assert_eq!(calc("1 + 2 * 3 << 1 | 1"), Ok(15));
+        assert_eq!(calc("~0 & 0xff"), Ok(0xff));
+        assert_eq!(
+            calc("(%hi(0x12348765) << 16) + %lo(0x12348765)"),
+            Ok(0x1234_8765)
+        );
+        assert_eq!(calc("%hi(0x12348765)"), Ok(0x1235));
+        assert_eq!(calc("%hi(0xdeadbeef)"), Ok(0xdeae));
+        assert_eq!(calc("%hi(-1)"), Ok(0));
+
+        assert_eq!(
+            calc("start+nowhere"),
+            Err(ExprError::new("undefined label `nowhere`", 6, 7))
+        );
+        assert_eq!(calc("(1+2"), Err(ExprError::new("unclosed `(`", 0, 4)));
+        assert_eq!(
+            calc("4/0").unwrap_err().message,
+            "`/` can't be applied to 4 and 0"
+        );
+        assert_eq!(
+            calc("1)").unwrap_err().message,
+            "unexpected `)` in expression"
+        );
+        assert_eq!(parse_int("12ab"), None);
+        assert_eq!(parse_int("-0x10"), Some(-16));
+    }
+}
@@ -0,0 +1,364 @@
+//! The canonical layout of source, for `mipsfmt`
+//!
+//! Every line is written in the original tab separated format, `label\top\targs...\tcomment`:
+//! * Labels are in the first column, without a `:`
+//! * Operands are separated by tabs, except the values of `.word`, and the parameters and arguments of macros,
+//!   which are separated by `, `
+//! * Registers are numbers, so `$t0` and `r8` are both `8`. Aliases and macro parameters are left alone
+//! * Integer literals are lowercase without leading zeros, so `0X1F` is `0x1f` and `007` is `7`. Other expressions are
+//!   left alone
+//! * Comments after code, `#` or free form, start at the same tab stop as the ones on the lines around them. Comments
+//!   on a line of their own are indented if they were
+//!
+//! Which fields are operands is decided the same way the assembler decides, so formatting doesn't change what a file
+//! assembles to, and formatting it again doesn't change it. `format_file` checks the first.
+
+use crate::asm::{assemble_in, jalr_second};
+use crate::data::Directive;
+use crate::expr;
+use crate::instr::{MathFunc, OpCode, RegImm};
+use crate::lexer::{Line, Token};
+use crate::mem::Endian;
+use crate::pseudo::Pseudo;
+use crate::reg;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+/// Columns between tab stops, for aligning comments
+pub const TAB_WIDTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    Reg,
+    Expr,
+    /// Names, strings, and anything that isn't understood
+    Verbatim,
+}
+
+/// What goes between operands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Separator {
+    Tab,
+    Comma,
+    /// Whatever the line had, for unknown mnemonics
+    AsWritten,
+}
+
+/// Format `source`
+pub fn format(source: &str) -> String {
+    // Parameter counts of the macros defined so far, and the aliases
+    let mut macros = HashMap::new();
+    let mut aliases = HashSet::new();
+    // Each line's code, and the comment after it
+    let mut lines: Vec<(String, Option<&str>)> = Vec::new();
+    for (line_num, text) in source.lines().enumerate() {
+        let line = Line::new(line_num, text);
+        if line.is_empty() {
+            let indent = if text.starts_with(|c: char| c.is_whitespace()) {
+                "\t"
+            } else {
+                ""
+            };
+            let code = line.comment.map_or_else(String::new, |comment| {
+                format!("{}{}", indent, comment.text.trim_end())
+            });
+            lines.push((code, None));
+            continue;
+        }
+
+        let mut code = line.label.map_or("", |label| label.text).to_owned();
+        let mut rest = &line.operands[..];
+        if let Some(op) = line.op {
+            code.push('\t');
+            code.push_str(op.text);
+            let (kinds, separator) = operands(op.text, &line.operands, &macros, &aliases);
+            if op.text == ".macro" {
+                if let Some(name) = line.operands.first() {
+                    macros.insert(name.text, line.operands.len() - 1);
+                }
+            }
+            if op.text == ".alias" {
+                if let Some(name) = line.operands.first() {
+                    aliases.insert(name.text);
+                }
+            }
+            let used = &line.operands[..kinds.len().min(line.operands.len())];
+            for (i, (tok, kind)) in used.iter().zip(kinds).enumerate() {
+                code.push_str(match separator {
+                    _ if i == 0 => "\t",
+                    // `.macro name params`
+                    Separator::Comma if i == 1 && op.text == ".macro" => " ",
+                    Separator::Comma => ", ",
+                    Separator::AsWritten if used[i - 1].comma => ", ",
+                    // `jalr rd rs` needs the comma if `rs` is an alias
+                    Separator::Tab
+                        if op.text == "jalr" && tok_text(*tok, kind).parse::<i64>().is_err() =>
+                    {
+                        ", "
+                    }
+                    _ => "\t",
+                });
+                code.push_str(&tok_text(*tok, kind));
+            }
+            // Keep a trailing comma, so a missing operand is still missing
+            if used.last().is_some_and(|last| last.comma) && separator != Separator::Tab {
+                code.push(',');
+            }
+            rest = &line.operands[used.len()..];
+        }
+        let comment = match rest.first() {
+            Some(first) => Some(text[first.span.col..].trim_end()),
+            None => line.comment.map(|comment| comment.text.trim_end()),
+        };
+        lines.push((code, comment));
+    }
+    align(&lines)
+}
+
+/// The operands of `op` and how they're separated, like the assembler reads them. Anything after them is a comment
+fn operands(
+    op: &str,
+    toks: &[Token],
+    macros: &HashMap<&str, usize>,
+    aliases: &HashSet<&str>,
+) -> (Vec<Operand>, Separator) {
+    use Operand::*;
+    let tab = |kinds: &[Operand]| (kinds.to_vec(), Separator::Tab);
+    if let Some(&params) = macros.get(op) {
+        return (vec![Verbatim; params], Separator::Comma);
+    }
+    if let Ok(func) = op.parse::<MathFunc>() {
+        return tab(if func.reads_hi_lo() {
+            &[Reg]
+        } else if func.writes_hi_lo() {
+            &[Reg, Reg]
+        } else if func.uses_shamt() {
+            &[Reg, Reg, Expr]
+        } else {
+            &[Reg, Reg, Reg]
+        });
+    }
+    if op.parse::<RegImm>().is_ok() {
+        return tab(&[Reg, Expr]);
+    }
+    if let Ok(opcode) = op.parse::<OpCode>() {
+        return match opcode {
+            OpCode::JALR => {
+                // The second field is only an operand if the assembler would read it as one
+                let second = match toks {
+                    [first, second, ..] => {
+                        jalr_second(*first, *second, |name| aliases.contains(name))
+                    }
+                    _ => false,
+                };
+                tab(if second { &[Reg, Reg] } else { &[Reg] })
+            }
+            OpCode::HALT | OpCode::SYSCALL | OpCode::BREAK => tab(&[]),
+            OpCode::MFC0 => tab(&[Reg, Reg]),
+            OpCode::LUI => tab(&[Reg, Expr]),
+            OpCode::J | OpCode::JAL => tab(&[Expr]),
+            OpCode::MATH | OpCode::REGIMM => (vec![Verbatim; toks.len()], Separator::AsWritten),
+            _ => tab(&[Reg, Reg, Expr]),
+        };
+    }
+    if let Ok(pseudo) = op.parse::<Pseudo>() {
+        return tab(match pseudo {
+            Pseudo::Nop => &[],
+            Pseudo::B => &[Expr],
+            Pseudo::Bnez | Pseudo::Li => &[Reg, Expr],
+            Pseudo::Mov | Pseudo::Neg | Pseudo::Not => &[Reg, Reg],
+        });
+    }
+    if let Ok(directive) = op.parse::<Directive>() {
+        return match directive {
+            // Values continue after commas
+            Directive::Word => {
+                let values = toks
+                    .iter()
+                    .position(|tok| !tok.comma)
+                    .map_or(toks.len(), |last| last + 1);
+                (vec![Expr; values], Separator::Comma)
+            }
+            Directive::Ascii | Directive::Asciiz => tab(&[Verbatim]),
+            _ => tab(&[Expr]),
+        };
+    }
+    match op {
+        ".alias" => tab(&[Verbatim, Reg]),
+        ".equ" => tab(&[Verbatim, Expr]),
+        ".rept" => tab(&[Expr]),
+        ".globl" | ".extern" | ".include" => tab(&[Verbatim]),
+        ".endm" | ".endr" => tab(&[]),
+        ".macro" => (vec![Verbatim; toks.len()], Separator::Comma),
+        _ => (vec![Verbatim; toks.len()], Separator::AsWritten),
+    }
+}
+
+/// How many of `toks` are operands of `op`, rather than a comment. `macros` has the parameter counts of the macros
+/// defined so far, and `aliases` the names of the aliases
+pub(crate) fn operand_count(
+    op: &str,
+    toks: &[Token],
+    macros: &HashMap<&str, usize>,
+    aliases: &HashSet<&str>,
+) -> usize {
+    operands(op, toks, macros, aliases).0.len().min(toks.len())
+}
+
+/// The canonical spelling of an operand
+fn tok_text(tok: Token, kind: Operand) -> String {
+    match kind {
+        Operand::Reg => {
+            reg::parse(tok.text).map_or_else(|| tok.text.to_owned(), |reg| reg.to_string())
+        }
+        Operand::Expr => number(tok.text).unwrap_or_else(|| tok.text.to_owned()),
+        Operand::Verbatim => tok.text.to_owned(),
+    }
+}
+
+/// The canonical spelling of an integer literal, or `None` if `text` isn't one. Characters are left alone
+fn number(text: &str) -> Option<String> {
+    expr::parse_int(text)?;
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", text),
+    };
+    if digits.starts_with('\'') {
+        return None;
+    }
+    let digits = digits.to_ascii_lowercase();
+    let (prefix, digits) = match digits.get(..2) {
+        Some(prefix @ ("0x" | "0b" | "0o")) => (prefix, &digits[2..]),
+        _ => ("", &digits[..]),
+    };
+    match digits.trim_start_matches('0') {
+        "" => Some(format!("{}0", prefix)),
+        digits => Some(format!("{}{}{}", sign, prefix, digits)),
+    }
+}
+
+/// Join the lines, starting the comments of each run of commented code at one tab stop
+fn align(lines: &[(String, Option<&str>)]) -> String {
+    let width = |text: &str| {
+        text.chars().fold(0, |col, c| {
+            if c == '\t' {
+                col / TAB_WIDTH * TAB_WIDTH + TAB_WIDTH
+            } else {
+                col + 1
+            }
+        })
+    };
+    let mut output = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let run = lines[i..]
+            .iter()
+            .take_while(|(code, comment)| !code.is_empty() && comment.is_some())
+            .count();
+        if run == 0 {
+            output.push_str(&lines[i].0);
+            output.push('\n');
+            i += 1;
+            continue;
+        }
+        let stop = lines[i..i + run]
+            .iter()
+            .map(|(code, _)| width(code))
+            .max()
+            .unwrap()
+            / TAB_WIDTH
+            + 1;
+        for (code, comment) in &lines[i..i + run] {
+            output.push_str(code);
+            output.push_str(&"\t".repeat(stop - width(code) / TAB_WIDTH));
+            output.push_str(comment.unwrap());
+            output.push('\n');
+        }
+        i += run;
+    }
+    output
+}
+
+/// Read and format the file at `path`, giving the source and the formatted source. If it assembles, the formatted
+/// source is checked to assemble to the same thing
+pub fn format_file(path: &Path) -> Result<(String, String)> {
+    let source = fs::read_to_string(path)?;
+    let formatted = format(&source);
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    // An object if it has to be linked
+    for object in [false, true] {
+        if let Ok(program) = assemble_in(&source, dir, object, Endian::default()) {
+            match assemble_in(&formatted, dir, object, Endian::default()) {
+                Ok(after)
+                    if after.words == program.words && after.relocations == program.relocations => {
+                }
+                _ => bail!(
+                    "formatting {} would change what it assembles to",
+                    path.display()
+                ),
+            }
+            break;
+        }
+    }
+    Ok((source, formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source = "\
+# a comment
+start:  addi r2, $zero, 0X1F   # r2 <- 31
+  loop: beqz 0 $t0 loop
+\tjalr\t$ra $t0
+\t.alias\ttarget\t5
+\tjalr\tr31 target
+\tjalr\t$3\tcall it, without linking
+\t.word   007,-0x10 , 'a'\tvalues
+\t.macro  m a,b
+\tadd\t\\a\t\\a\t\\b
+\t.endm
+\tm\tr2,\t3\tfree form comment
+\tunknown\t1, 2\t3
+";
+        assert_eq!(
+            format(source),
+            "\
+# a comment
+start\taddi\t2\t0\t0x1f\t# r2 <- 31
+loop\tbeqz\t0\t8\tloop
+\tjalr\t31\t8
+\t.alias\ttarget\t5
+\tjalr\t31, target
+\tjalr\t3\t\tcall it, without linking
+\t.word\t7, -0x10, 'a'\tvalues
+\t.macro\tm a, b
+\tadd\t\\a\t\\a\t\\b
+\t.endm
+\tm\tr2, 3\tfree form comment
+\tunknown\t1, 2\t3
+"
+        );
+    }
+
+    #[test]
+    fn test_public_tests() -> Result<()> {
+        let tests = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests");
+        for entry in fs::read_dir(tests)? {
+            let path = entry?.path();
+            if path.extension() != Some("mips".as_ref()) {
+                continue;
+            }
+            let (_, formatted) = format_file(&path)?;
+            assert_eq!(format(&formatted), formatted, "{}", path.display());
+        }
+        Ok(())
+    }
+}
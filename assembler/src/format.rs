@@ -0,0 +1,167 @@
+//! Formats machine code can be written in, picked with `--format`
+//!
+//! | format    | output                                                                |
+//! |-----------|-----------------------------------------------------------------------|
+//! | `hex`     | one `{:08x}` word per line, which the simulators read                 |
+//! | `bin-le`  | raw bytes, little endian                                              |
+//! | `bin-be`  | raw bytes, big endian                                                 |
+//! | `ihex`    | Intel HEX, 16 bytes per record, little endian                         |
+//! | `logisim` | Logisim's `v2.0 raw` memory image, with runs written as `count*word` |
+//! | `memh`    | Verilog `$readmemh`, starting with `@0` and one word per line         |
+//! | `c`       | a C `uint32_t` array                                                  |
+//! | `rust`    | a Rust `u32` array `const`                                            |
+
+use std::io::{self, Write};
+
+use strum_macros::{Display, EnumString};
+
+#[derive(EnumString, Display, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Format {
+    #[default]
+    Hex,
+    BinLe,
+    BinBe,
+    Ihex,
+    Logisim,
+    Memh,
+    C,
+    Rust,
+}
+
+/// Words per line of Logisim, C and Rust output
+const WORDS_PER_LINE: usize = 8;
+
+/// Bytes per Intel HEX data record
+const IHEX_RECORD: usize = 16;
+
+impl Format {
+    pub fn write(self, words: &[u32], mut output: impl Write) -> io::Result<()> {
+        match self {
+            Format::Hex => {
+                for word in words {
+                    writeln!(output, "{:08x}", word)?;
+                }
+            }
+            Format::BinLe | Format::BinBe => {
+                for word in words {
+                    let bytes = if self == Format::BinLe {
+                        word.to_le_bytes()
+                    } else {
+                        word.to_be_bytes()
+                    };
+                    output.write_all(&bytes)?;
+                }
+            }
+            Format::Ihex => write_ihex(words, output)?,
+            Format::Logisim => {
+                writeln!(output, "v2.0 raw")?;
+                let mut runs = Vec::new();
+                let mut rest = words;
+                while let Some(&word) = rest.first() {
+                    let count = rest.iter().take_while(|&&other| other == word).count();
+                    runs.push(if count > 1 {
+                        format!("{}*{:x}", count, word)
+                    } else {
+                        format!("{:x}", word)
+                    });
+                    rest = &rest[count..];
+                }
+                for line in runs.chunks(WORDS_PER_LINE) {
+                    writeln!(output, "{}", line.join(" "))?;
+                }
+            }
+            Format::Memh => {
+                writeln!(output, "@0")?;
+                for word in words {
+                    writeln!(output, "{:08x}", word)?;
+                }
+            }
+            Format::C | Format::Rust => {
+                if self == Format::C {
+                    writeln!(output, "#include <stdint.h>\n")?;
+                    writeln!(output, "const uint32_t program[{}] = {{", words.len())?;
+                } else {
+                    writeln!(output, "pub const PROGRAM: [u32; {}] = [", words.len())?;
+                }
+                for line in words.chunks(WORDS_PER_LINE) {
+                    let line: Vec<_> = line.iter().map(|word| format!("0x{:08x},", word)).collect();
+                    writeln!(output, "    {}", line.join(" "))?;
+                }
+                writeln!(output, "{}", if self == Format::C { "};" } else { "];" })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Intel HEX records of the words' little endian bytes
+fn write_ihex(words: &[u32], mut output: impl Write) -> io::Result<()> {
+    fn record(output: &mut impl Write, addr: u16, kind: u8, data: &[u8]) -> io::Result<()> {
+        let [hi, lo] = addr.to_be_bytes();
+        let mut sum = (data.len() as u8)
+            .wrapping_add(hi)
+            .wrapping_add(lo)
+            .wrapping_add(kind);
+        write!(output, ":{:02X}{:04X}{:02X}", data.len(), addr, kind)?;
+        for &byte in data {
+            sum = sum.wrapping_add(byte);
+            write!(output, "{:02X}", byte)?;
+        }
+        writeln!(output, "{:02X}", sum.wrapping_neg())
+    }
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let mut upper = 0;
+    for (i, data) in bytes.chunks(IHEX_RECORD).enumerate() {
+        let addr = (i * IHEX_RECORD) as u32;
+        // Addresses past 64K need an extended linear address record for their upper half
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            record(&mut output, 0, 0x04, &(upper as u16).to_be_bytes())?;
+        }
+        record(&mut output, addr as u16, 0x00, data)?;
+    }
+    record(&mut output, 0, 0x01, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(format: &str, words: &[u32]) -> Vec<u8> {
+        let mut output = Vec::new();
+        format
+            .parse::<Format>()
+            .unwrap()
+            .write(words, &mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_formats() {
+        let words = [0x2002_0005, 0, 0, 0, 0xfc00_0000];
+        let text = |format| String::from_utf8(write(format, &words)).unwrap();
+        assert_eq!(write("bin-le", &words[..1]), [0x05, 0x00, 0x02, 0x20]);
+        assert_eq!(write("bin-be", &words[..1]), [0x20, 0x02, 0x00, 0x05]);
+        assert_eq!(
+            text("ihex"),
+            ":1000000005000220000000000000000000000000C9\n\
+             :04001000000000FCF0\n\
+             :00000001FF\n"
+        );
+        assert_eq!(text("logisim"), "v2.0 raw\n20020005 3*0 fc000000\n");
+        assert_eq!(
+            text("memh"),
+            "@0\n20020005\n00000000\n00000000\n00000000\nfc000000\n"
+        );
+        assert_eq!(
+            text("rust"),
+            "pub const PROGRAM: [u32; 5] = [\n    \
+             0x20020005, 0x00000000, 0x00000000, 0x00000000, 0xfc000000,\n];\n"
+        );
+        assert!(text("c").contains("const uint32_t program[5] = {\n"));
+        assert!("pdf".parse::<Format>().is_err());
+    }
+}
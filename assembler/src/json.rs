@@ -0,0 +1,349 @@
+//! Just enough JSON for the language server's JSON-RPC messages
+//!
+//! Numbers are `f64`, like JavaScript's, and objects keep their keys sorted. Strings are escaped as JSON requires,
+//! including surrogate pairs in `\u` escapes.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Write};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+/// Returned by indexing a missing key or element, so lookups can be chained
+static NULL: Json = Json::Null;
+
+impl Json {
+    /// An object of `(key, value)` pairs
+    pub fn object<'k>(fields: impl IntoIterator<Item = (&'k str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect(),
+        )
+    }
+
+    /// The value of `key`, or `Null` if this isn't an object or doesn't have it
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields.get(key).unwrap_or(&NULL),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The number if it's a non-negative integer
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    /// Parse a whole JSON document
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_space();
+        if parser.pos < text.len() {
+            return Err(parser.error("unexpected text after the value"));
+        }
+        Ok(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            // JSON has no infinities or NaN
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    /// Byte offset of the next character
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.pos)
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", token)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        if self.eat("null") {
+            Ok(Json::Null)
+        } else if self.eat("true") {
+            Ok(Json::Bool(true))
+        } else if self.eat("false") {
+            Ok(Json::Bool(false))
+        } else if rest.starts_with('"') {
+            self.string().map(Json::String)
+        } else if self.eat("[") {
+            let mut items = Vec::new();
+            if !self.eat("]") {
+                loop {
+                    items.push(self.value()?);
+                    if self.eat("]") {
+                        break;
+                    }
+                    self.expect(",")?;
+                }
+            }
+            Ok(Json::Array(items))
+        } else if self.eat("{") {
+            let mut fields = BTreeMap::new();
+            if !self.eat("}") {
+                loop {
+                    self.skip_space();
+                    let key = self.string()?;
+                    self.expect(":")?;
+                    fields.insert(key, self.value()?);
+                    if self.eat("}") {
+                        break;
+                    }
+                    self.expect(",")?;
+                }
+            }
+            Ok(Json::Object(fields))
+        } else {
+            let len = rest
+                .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+                .unwrap_or(rest.len());
+            match rest[..len].parse() {
+                Ok(n) if len > 0 => {
+                    self.pos += len;
+                    Ok(Json::Number(n))
+                }
+                _ => Err(self.error("expected a value")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if !self.text[self.pos..].starts_with('"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut s = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let code = match hex(&mut chars) {
+                                // A surrogate pair is two escapes
+                                Some(high @ 0xd800..=0xdbff) => {
+                                    match (chars.next(), chars.next(), hex(&mut chars)) {
+                                        (Some((_, '\\')), Some((_, 'u')), Some(low)) => Some(
+                                            0x10000
+                                                + ((high - 0xd800) << 10)
+                                                + (low.wrapping_sub(0xdc00)),
+                                        ),
+                                        _ => None,
+                                    }
+                                }
+                                unit => unit,
+                            };
+                            match code.and_then(char::from_u32) {
+                                Some(c) => c,
+                                None => {
+                                    self.pos += i;
+                                    return Err(self.error("invalid unicode escape"));
+                                }
+                            }
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        _ => {
+                            self.pos += i;
+                            return Err(self.error("invalid escape"));
+                        }
+                    };
+                    s.push(escaped);
+                }
+                c => s.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+/// The 4 hex digits of a `\u` escape
+fn hex(chars: &mut std::str::CharIndices) -> Option<u32> {
+    let digits: String = chars.take(4).map(|(_, c)| c).collect();
+    u32::from_str_radix(&digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let text =
+            r#" {"id": 1, "params": {"text": "a\tb \"c\" é😀", "list": [true, null, -2.5e1]}} "#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("id").as_usize(), Some(1));
+        assert_eq!(
+            json.get("params").get("text").as_str(),
+            Some("a\tb \"c\" é😀")
+        );
+        assert_eq!(
+            json.get("params").get("list").as_array()[2],
+            Json::Number(-25.0)
+        );
+        assert_eq!(json.get("missing").get("key"), &Json::Null);
+        assert_eq!(
+            json.to_string(),
+            r#"{"id":1,"params":{"list":[true,null,-25],"text":"a\tb \"c\" é😀"}}"#
+        );
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+        assert_eq!(
+            Json::parse(r#""\u00e9\ud83d\ude00""#),
+            Ok(Json::from("é😀"))
+        );
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("{\"a\" 1}").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Splits `.mips` source into labels, mnemonics and operands.
+//!
+//! The original format is tab separated columns: `label\top\targs...\tcomment`. The lexer is more forgiving:
+//! * Fields are separated by any whitespace, or commas
+//! * A label is either in the first column (the line doesn't start with whitespace), or ends in `:`
+//! * `#` and `;` start a comment. The original free form comment column still works, because the parser
+//!   only takes as many operands as the instruction needs and ignores the rest
+//! * Parentheses and quotes group a field, so `(end - start)/4` and `"a b"` are single fields
+
+use crate::diag::Span;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub span: Span,
+    /// Whether the field was followed by a comma, meaning another operand is coming
+    pub comma: bool,
+}
+
+/// One line of source
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Line<'a> {
+    /// Without the `:`, if it had one
+    pub label: Option<Token<'a>>,
+    pub op: Option<Token<'a>>,
+    /// Every field after the mnemonic. The parser decides which are operands, the rest are comments
+    pub operands: Vec<Token<'a>>,
+    /// A `#` or `;` comment
+    pub comment: Option<Token<'a>>,
+}
+
+impl<'a> Line<'a> {
+    /// Lex line number `line_num` (from 0), `text` shouldn't include the newline
+    pub fn new(line_num: usize, text: &'a str) -> Self {
+        let mut fields: Vec<Token> = Vec::new();
+        let mut comment = None;
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b' ' | b'\t' | b'\r' => i += 1,
+                b',' => {
+                    if let Some(last) = fields.last_mut() {
+                        last.comma = true;
+                    }
+                    i += 1;
+                }
+                b'#' | b';' => {
+                    comment = Some(Token {
+                        text: &text[i..],
+                        span: Span::new(line_num, i, text.len() - i),
+                        comma: false,
+                    });
+                    break;
+                }
+                _ => {
+                    let end = field_end(bytes, i);
+                    fields.push(Token {
+                        text: &text[i..end],
+                        span: Span::new(line_num, i, end - i),
+                        comma: false,
+                    });
+                    i = end;
+                }
+            }
+        }
+
+        let mut fields = fields.into_iter().peekable();
+        let label = match fields.peek() {
+            Some(first) if first.text.ends_with(':') => {
+                let first = fields.next().unwrap();
+                Some(Token {
+                    text: &first.text[..first.text.len() - 1],
+                    span: Span {
+                        len: first.span.len - 1,
+                        ..first.span
+                    },
+                    ..first
+                })
+            }
+            // The original format's label column
+            Some(first) if first.span.col == 0 => fields.next(),
+            _ => None,
+        };
+        let op = fields.next();
+
+        Self {
+            label,
+            op,
+            operands: fields.collect(),
+            comment,
+        }
+    }
+
+    /// Whether the line has nothing but whitespace and comments
+    pub fn is_empty(&self) -> bool {
+        self.label.is_none() && self.op.is_none()
+    }
+}
+
+/// Lex every line of `source`
+pub fn lex(source: &str) -> impl Iterator<Item = Line<'_>> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| Line::new(line_num, line))
+}
+
+/// Find where the field starting at `start` ends. Quotes and parentheses can contain separators
+fn field_end(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut i = start;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match quote {
+            Some(q) => {
+                if c == b'\\' {
+                    i += 1;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                b'"' | b'\'' => quote = Some(c),
+                b'(' => depth += 1,
+                b')' => depth = depth.saturating_sub(1),
+                b' ' | b'\t' | b'\r' | b',' | b'#' | b';' if depth == 0 => return i,
+                _ => {}
+            },
+        }
+        i += 1;
+    }
+    // Unterminated quotes and parentheses run to the end of the line. The parser complains if they're used
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts<'a>(tokens: &[Token<'a>]) -> Vec<&'a str> {
+        tokens.iter().map(|t| t.text).collect()
+    }
+
+    #[test]
+    fn test_tab_columns() {
+        let line = Line::new(
+            0,
+            "loop\tbeqz\t0\t1\tloop\tif r1==0\ttest line(negative offset, and is taken)",
+        );
+        assert_eq!(line.label.unwrap().text, "loop");
+        assert_eq!(line.op.unwrap().text, "beqz");
+        assert_eq!(texts(&line.operands)[..4], ["0", "1", "loop", "if"]);
+        assert_eq!(line.operands[2].span, Span::new(0, 14, 4));
+
+        let line = Line::new(0, "\thalt");
+        assert_eq!(line.label, None);
+        assert_eq!(line.op.unwrap().text, "halt");
+    }
+
+    #[test]
+    fn test_free_form() {
+        let line = Line::new(3, "  end:  add r1, r2,r3 # sum");
+        assert_eq!(line.label.unwrap().text, "end");
+        assert_eq!(line.label.unwrap().span, Span::new(3, 2, 3));
+        assert_eq!(texts(&line.operands), ["r1", "r2", "r3"]);
+        assert!(line.operands[0].comma && line.operands[1].comma && !line.operands[2].comma);
+        assert_eq!(line.comment.unwrap().text, "# sum");
+
+        let line = Line::new(0, "\t.word\t(end - start)/4, ';'\t; done");
+        assert_eq!(texts(&line.operands), ["(end - start)/4", "';'"]);
+
+        assert!(Line::new(0, "   ; just a comment").is_empty());
+        assert!(Line::new(0, "").is_empty());
+        let line = Line::new(0, "alone:");
+        assert_eq!((line.label.unwrap().text, line.op), ("alone", None));
+    }
+}
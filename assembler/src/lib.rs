@@ -1 +1,23 @@
+pub mod asm;
+pub mod cp0;
+pub mod data;
+pub mod diag;
+pub mod disasm;
+pub mod expr;
+pub mod fmt;
+pub mod format;
 pub mod instr;
+pub mod json;
+pub mod lexer;
+pub mod lint;
+pub mod lsp;
+pub mod macros;
+pub mod mem;
+pub mod object;
+pub mod pseudo;
+pub mod reg;
+pub mod schedule;
+
+pub use asm::{
+    assemble, assemble_file, assemble_object, assemble_object_file, assemble_path, Program,
+};
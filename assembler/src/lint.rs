@@ -0,0 +1,200 @@
+//! Static checks of an assembled program, for `asmlint`
+//!
+//! * note: an instruction reading the register loaded by the `lw` just before it, which `pipe` stalls a cycle for
+//! * warning: an instruction writing r0, which has no effect. `nop` and `jalr 0 rs`, a jump that doesn't link, are
+//!   fine
+//! * warning: unreachable code after a `halt`, an unconditional branch, a `j`, or a `jalr` that doesn't link, up to
+//!   the next label or branch target
+//! * error: a branch into data, or outside of the program
+//! * error: execution falling through from an instruction into data, which traps
+//!
+//! Anything that's labelled might be jumped to by a `jalr`, so it's assumed to be reachable.
+
+use crate::asm::Program;
+use crate::diag::{Diagnostic, Diagnostics, Span};
+use crate::instr::{Instruction, Word};
+
+use std::collections::BTreeSet;
+
+/// How execution can reach the next word
+enum Flow {
+    /// By falling through from the word on this line, or from the start if there isn't one
+    Falls(Option<usize>),
+    /// It can't, because of this instruction or data
+    Stopped(String),
+    /// It can't, and that's already been reported
+    Unreachable,
+}
+
+/// Check `program`, which was assembled from `source`
+pub fn lint(program: &Program, source: &str) -> Diagnostics {
+    let mut diags = Diagnostics::default();
+    let len = 4 * program.words.len() as u32;
+    let words: Vec<Word> = program.words.iter().map(|&bits| Word::from(bits)).collect();
+    let line_of = |addr: u32| program.source_map[(addr / 4) as usize];
+    // The whole line, since there isn't a span for the words of one
+    let span = |addr: u32| {
+        let line = line_of(addr);
+        let text = source.lines().nth(line).unwrap_or("").trim_end();
+        let col = text.len() - text.trim_start().len();
+        Span::new(line, col, text.len() - col)
+    };
+
+    let branch_target = |addr: u32, word: &Word| match word {
+        Word::Instr(instr) if !program.data.contains(&addr) => instr.target(addr),
+        _ => None,
+    };
+    let mut entries: BTreeSet<i64> = program
+        .symbols
+        .values()
+        .map(|&addr| i64::from(addr))
+        .collect();
+    entries.extend(
+        (0..)
+            .step_by(4)
+            .zip(&words)
+            .filter_map(|(addr, word)| branch_target(addr, word)),
+    );
+
+    let mut flow = Flow::Falls(None);
+    for (addr, word) in (0..).step_by(4).zip(&words) {
+        let entered = entries.contains(&i64::from(addr));
+        if program.data.contains(&addr) {
+            if let Flow::Falls(from) = flow {
+                let diag = Diagnostic::error(span(addr), "execution falls through into data");
+                diags.push(match from {
+                    Some(line) => {
+                        diag.with_note(format!("after the instruction on line {}", line + 1))
+                    }
+                    None => diag.with_note("execution starts at address 0"),
+                });
+            }
+            flow = Flow::Stopped("data".to_owned());
+            continue;
+        }
+
+        let previous = match flow {
+            Flow::Stopped(after) if !entered => {
+                diags.push(Diagnostic::warning(
+                    span(addr),
+                    format!("unreachable code after {}", after),
+                ));
+                flow = Flow::Unreachable;
+                continue;
+            }
+            Flow::Unreachable if !entered => continue,
+            Flow::Falls(Some(_)) => addr.checked_sub(4).map(|prev| &words[(prev / 4) as usize]),
+            _ => None,
+        };
+        let instr = match word {
+            Word::Instr(instr) => *instr,
+            // Assembled code always decodes, this was linked from something else
+            Word::Data(..) => {
+                flow = Flow::Falls(Some(line_of(addr)));
+                continue;
+            }
+        };
+
+        if let Some(Word::Instr(Instruction::Load { rt, .. })) = previous {
+            if instr.sources().contains(&Some(*rt)) {
+                diags.push(
+                    Diagnostic::note(
+                        span(addr),
+                        format!("`{}` reads r{} right after it's loaded, so `pipe` stalls for a cycle", instr, rt),
+                    )
+                    .with_note(format!(
+                        "the load is on line {}, an independent instruction between them would fill the stall",
+                        line_of(addr - 4) + 1
+                    )),
+                );
+            }
+        }
+
+        let nop = instr == Instruction::nop();
+        if instr.dest() == Some(0) && !nop && !matches!(instr, Instruction::Jalr { .. }) {
+            diags.push(Diagnostic::warning(
+                span(addr),
+                format!("`{}` writes r0, which is always 0", instr),
+            ));
+        }
+
+        if let Some(target) = branch_target(addr, word) {
+            if target < 0 || target >= i64::from(len) {
+                diags.push(
+                    Diagnostic::error(span(addr), "branch target is outside of the program")
+                        .with_note(format!("it's {}, and the program ends at {}", target, len)),
+                );
+            } else if program.data.contains(&(target as u32)) {
+                diags.push(
+                    Diagnostic::error(span(addr), "branch into data").with_note(format!(
+                        "address {} is data from line {}",
+                        target,
+                        line_of(target as u32) + 1
+                    )),
+                );
+            }
+        }
+
+        flow = match instr {
+            Instruction::Halt
+            | Instruction::Jump { link: false, .. }
+            | Instruction::Jalr { rd: 0, .. } => Flow::Stopped(format!("`{}`", instr)),
+            _ if instr.always_taken() => Flow::Stopped(format!("`{}`", instr)),
+            _ => Flow::Falls(Some(line_of(addr))),
+        };
+    }
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn test_lint() {
+        let source = "\
+\tlw\t2\t0\tnum
+\tadd\t3\t2\t2
+\tadd\t0\t3\t3
+\tbeqz\t0\t3\tnum
+\tnop
+\thalt
+\taddi\t3\t3\t1
+num\t.fill\t4
+";
+        let program = assemble(source).unwrap();
+        let diags = lint(&program, source);
+        let found: Vec<_> = diags
+            .iter()
+            .map(|d| (d.severity.to_string(), d.span.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    "note".to_owned(),
+                    1,
+                    "`add 3 2 2` reads r2 right after it's loaded, so `pipe` stalls for a cycle"
+                ),
+                (
+                    "warning".to_owned(),
+                    2,
+                    "`add 0 3 3` writes r0, which is always 0"
+                ),
+                ("error".to_owned(), 3, "branch into data"),
+                ("warning".to_owned(), 6, "unreachable code after `halt`"),
+            ]
+        );
+        assert_eq!(diags.iter().nth(3).unwrap().span, Span::new(6, 1, 10));
+
+        let diags = lint(
+            &assemble("\tnop\n\t.fill\t1\n").unwrap(),
+            "\tnop\n\t.fill\t1\n",
+        );
+        assert_eq!(
+            diags.iter().next().unwrap().message,
+            "execution falls through into data"
+        );
+    }
+}
@@ -0,0 +1,660 @@
+//! A language server for `.mips` files, for `mips-lsp`
+//!
+//! It speaks JSON-RPC over stdio, and supports:
+//! * Diagnostics from the assembler, and from `asmlint` once a file assembles, whenever a file is opened or changed.
+//!   Files with an `.extern` are assembled as objects
+//! * Go to definition and find references for labels and `.equ`s
+//! * Hover, showing the address, encoding and disassembly of each word a line assembles to
+//! * Completion for the mnemonics of real instructions
+//!
+//! Documents are synced in full. Positions are converted between the UTF-16 columns of the protocol and the byte
+//! columns of `Span`s.
+
+use crate::asm::{assemble_in, Program};
+use crate::diag::{Diagnostic, Severity, Span};
+use crate::fmt::operand_count;
+use crate::instr::{MathFunc, OpCode, RegImm, Word};
+use crate::json::Json;
+use crate::lexer::Line;
+use crate::lint::lint;
+use crate::mem::Endian;
+use crate::reg;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use strum::IntoEnumIterator;
+
+/// JSON-RPC error codes
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// A JSON-RPC error code and message
+type RpcError = (i64, String);
+
+/// `CompletionItemKind.Keyword`
+const KEYWORD: usize = 14;
+
+struct Document {
+    text: String,
+    /// `None` if it has errors
+    program: Option<Program>,
+}
+
+/// A label or `.equ` name in a document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Occurrence<'a> {
+    name: &'a str,
+    span: Span,
+    definition: bool,
+}
+
+impl Occurrence<'_> {
+    /// Whether it's at byte column `col` of `line`, including just after it
+    fn contains(&self, line: usize, col: usize) -> bool {
+        self.span.line == line && (self.span.col..=self.span.col + self.span.len).contains(&col)
+    }
+}
+
+#[derive(Default)]
+pub struct Server {
+    /// Open documents by URI
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Server {
+    /// Handle a request or notification, giving the messages to send back
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        let id = message.get("id");
+        let result = match method {
+            "initialize" => Ok(Json::object([(
+                "capabilities",
+                Json::object([
+                    // Full document sync
+                    ("textDocumentSync", Json::from(1usize)),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    ("hoverProvider", true.into()),
+                    ("completionProvider", Json::object([])),
+                ]),
+            )])),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "exit" => {
+                self.exited = true;
+                return Vec::new();
+            }
+            _ if self.shutdown && *id != Json::Null => {
+                Err((INVALID_REQUEST, "the server is shut down".to_owned()))
+            }
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let uri = params
+                    .get("textDocument")
+                    .get("uri")
+                    .as_str()
+                    .unwrap_or_default();
+                let text = match params.get("contentChanges").as_array().last() {
+                    Some(change) => change.get("text"),
+                    None => params.get("textDocument").get("text"),
+                };
+                return match text.as_str() {
+                    Some(text) => vec![self.update(uri, text.to_owned())],
+                    None => Vec::new(),
+                };
+            }
+            "textDocument/didClose" => {
+                let uri = params
+                    .get("textDocument")
+                    .get("uri")
+                    .as_str()
+                    .unwrap_or_default();
+                self.documents.remove(uri);
+                return vec![publish(uri, Vec::new())];
+            }
+            "textDocument/definition" | "textDocument/references" => {
+                self.position(params).map(|(uri, doc, line, col)| {
+                    let all = occurrences(&doc.text);
+                    let name = all.iter().find(|o| o.contains(line, col)).map(|o| o.name);
+                    let declaration = method == "textDocument/definition"
+                        || params
+                            .get("context")
+                            .get("includeDeclaration")
+                            .as_bool()
+                            .unwrap_or(true);
+                    let mut locations = all
+                        .iter()
+                        .filter(|o| {
+                            Some(o.name) == name
+                                && (o.definition || method == "textDocument/references")
+                        })
+                        .filter(|o| declaration || !o.definition)
+                        .map(|o| location(uri, &doc.text, o.span));
+                    if method == "textDocument/definition" {
+                        locations.next().unwrap_or(Json::Null)
+                    } else {
+                        Json::Array(locations.collect())
+                    }
+                })
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => Ok(Json::Array(completions())),
+            // Notifications that don't need anything, like `initialized`
+            _ if *id == Json::Null => return Vec::new(),
+            _ => Err((METHOD_NOT_FOUND, format!("`{}` isn't supported", method))),
+        };
+        let response = match result {
+            Ok(result) => ("result", result),
+            Err((code, message)) => (
+                "error",
+                Json::object([("code", code.into()), ("message", message.into())]),
+            ),
+        };
+        vec![Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.clone()),
+            response,
+        ])]
+    }
+
+    /// Whether `exit` was received
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Whether `shutdown` was received, so exiting is expected
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
+
+    /// Store and assemble a new version of a document, giving its diagnostics
+    fn update(&mut self, uri: &str, text: String) -> Json {
+        let dir = path(uri)
+            .and_then(|path| path.parent().map(PathBuf::from))
+            .unwrap_or_default();
+        let object = text
+            .lines()
+            .any(|line| Line::new(0, line).op.is_some_and(|op| op.text == ".extern"));
+        let (program, diags) = match assemble_in(&text, &dir, object, Endian::default()) {
+            Ok(program) => {
+                let diags = lint(&program, &text);
+                (Some(program), diags)
+            }
+            Err(diags) => (None, diags),
+        };
+        let diagnostics = diags.iter().map(|diag| diagnostic(&text, diag)).collect();
+        self.documents
+            .insert(uri.to_owned(), Document { text, program });
+        publish(uri, diagnostics)
+    }
+
+    /// The document, line and byte column of a `TextDocumentPositionParams`
+    fn position<'s>(
+        &'s self,
+        params: &'s Json,
+    ) -> Result<(&'s str, &'s Document, usize, usize), RpcError> {
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or_default();
+        let doc = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("`{}` isn't open", uri)))?;
+        let position = params.get("position");
+        let (line, character) = position
+            .get("line")
+            .as_usize()
+            .zip(position.get("character").as_usize())
+            .ok_or_else(|| (INVALID_PARAMS, "expected a position".to_owned()))?;
+        let text = doc.text.lines().nth(line).unwrap_or_default();
+        Ok((uri, doc, line, byte_col(text, character)))
+    }
+
+    /// The words the line assembles to, and the address of a label under the cursor
+    fn hover(&self, params: &Json) -> Result<Json, RpcError> {
+        let (_, doc, line, col) = self.position(params)?;
+        let program = match &doc.program {
+            Some(program) => program,
+            None => return Ok(Json::Null),
+        };
+        let mut contents = Vec::new();
+        let label = occurrences(&doc.text)
+            .into_iter()
+            .find(|o| o.contains(line, col));
+        if let Some((name, addr)) =
+            label.and_then(|o| Some(o.name).zip(program.symbols.get(o.name)))
+        {
+            contents.push(format!("`{}` is at {:#010x}\n", name, addr));
+        }
+        let words: Vec<String> = (0..)
+            .step_by(4)
+            .zip(&program.words)
+            .zip(&program.source_map)
+            .filter(|&(_, &source_line)| source_line == line)
+            .map(|((addr, &bits), _)| format!("{:08x}  {:08x}  {}", addr, bits, Word::from(bits)))
+            .collect();
+        if !words.is_empty() {
+            contents.push(format!("```\n{}\n```", words.join("\n")));
+        }
+        if contents.is_empty() {
+            return Ok(Json::Null);
+        }
+        Ok(Json::object([(
+            "contents",
+            Json::object([
+                ("kind", "markdown".into()),
+                ("value", contents.join("\n").into()),
+            ]),
+        )]))
+    }
+}
+
+/// Serve requests from `input` until `exit`, or the input ends. `Ok(false)` if that wasn't after a `shutdown`
+pub fn run(mut input: impl BufRead, mut output: impl Write) -> Result<bool> {
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut input)? {
+        let message = match Json::parse(&message) {
+            Ok(message) => message,
+            Err(err) => {
+                let error =
+                    Json::object([("code", Json::from(-32700i64)), ("message", err.into())]);
+                let response = Json::object([
+                    ("jsonrpc", "2.0".into()),
+                    ("id", Json::Null),
+                    ("error", error),
+                ]);
+                write_message(&mut output, &response)?;
+                continue;
+            }
+        };
+        for response in server.handle(&message) {
+            write_message(&mut output, &response)?;
+        }
+        if server.exited() {
+            break;
+        }
+    }
+    Ok(server.is_shutdown())
+}
+
+/// Read a message's `Content-Length` header and content, `None` at the end of input
+pub fn read_message(input: &mut impl BufRead) -> Result<Option<String>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .context("invalid Content-Length")?,
+                );
+            }
+        }
+    }
+    let len = match len {
+        Some(len) => len,
+        None => bail!("a message is missing its Content-Length"),
+    };
+    let mut content = vec![0; len];
+    input.read_exact(&mut content)?;
+    Ok(Some(String::from_utf8(content)?))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Json) -> Result<()> {
+    let content = message.to_string();
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    output.flush()?;
+    Ok(())
+}
+
+/// A `textDocument/publishDiagnostics` notification
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+        ),
+    ])
+}
+
+fn diagnostic(text: &str, diag: &Diagnostic) -> Json {
+    let severity = match diag.severity {
+        Severity::Error => 1usize,
+        Severity::Warning => 2,
+        Severity::Note => 3,
+    };
+    let mut message = diag.message.clone();
+    for note in &diag.notes {
+        message.push_str("\nnote: ");
+        message.push_str(note);
+    }
+    Json::object([
+        ("range", range(text, diag.span)),
+        ("severity", severity.into()),
+        ("source", "assembler".into()),
+        ("message", message.into()),
+    ])
+}
+
+fn location(uri: &str, text: &str, span: Span) -> Json {
+    Json::object([("uri", uri.into()), ("range", range(text, span))])
+}
+
+/// The protocol's range for `span` in `text`
+fn range(text: &str, span: Span) -> Json {
+    let line = text.lines().nth(span.line).unwrap_or_default();
+    let position = |col: usize| {
+        let col = col.min(line.len());
+        let character = line
+            .get(..col)
+            .map_or(col, |before| before.encode_utf16().count());
+        Json::object([("line", span.line.into()), ("character", character.into())])
+    };
+    Json::object([
+        ("start", position(span.col)),
+        ("end", position(span.col + span.len)),
+    ])
+}
+
+/// The byte column of the UTF-16 column `character` in `line`
+fn byte_col(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (col, c) in line.char_indices() {
+        if units >= character {
+            return col;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+/// The path of a `file://` URI
+fn path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded
+            .get(i + 1..i + 3)
+            .filter(|_| encoded[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Every definition and use of a label or `.equ` in `text`. Uses are names in operands, other than registers,
+/// macro parameters, and `%hi`/`%lo`
+fn occurrences(text: &str) -> Vec<Occurrence<'_>> {
+    let mut macros = HashMap::new();
+    let mut aliases = HashSet::new();
+    let mut found = Vec::new();
+    for (line_num, line_text) in text.lines().enumerate() {
+        let line = Line::new(line_num, line_text);
+        if let Some(label) = line.label {
+            found.push(Occurrence {
+                name: label.text,
+                span: label.span,
+                definition: true,
+            });
+        }
+        let op = match line.op {
+            Some(op) => op,
+            None => continue,
+        };
+        if op.text == ".macro" {
+            if let Some(name) = line.operands.first() {
+                macros.insert(name.text, line.operands.len() - 1);
+            }
+            continue;
+        }
+        if op.text == ".alias" {
+            if let Some(name) = line.operands.first() {
+                aliases.insert(name.text);
+            }
+        }
+        let count = operand_count(op.text, &line.operands, &macros, &aliases);
+        for (i, tok) in line.operands[..count].iter().enumerate() {
+            if (op.text == ".equ" || op.text == ".alias") && i == 0 {
+                if op.text == ".equ" {
+                    found.push(Occurrence {
+                        name: tok.text,
+                        span: tok.span,
+                        definition: true,
+                    });
+                }
+                continue;
+            }
+            if tok.text.starts_with('"') {
+                continue;
+            }
+            found.extend(
+                names(tok.text)
+                    .filter(|(_, name)| reg::parse(name).is_none())
+                    .map(|(col, name)| Occurrence {
+                        name,
+                        span: Span::new(line_num, tok.span.col + col, name.len()),
+                        definition: false,
+                    }),
+            );
+        }
+    }
+    found
+}
+
+/// The names in an expression, and their offsets
+fn names(expr: &str) -> impl Iterator<Item = (usize, &str)> {
+    let bytes = expr.as_bytes();
+    let name_byte = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'.';
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            // A character literal
+            b'\'' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'\'' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b if name_byte(b) => {
+                while i < bytes.len() && name_byte(bytes[i]) {
+                    i += 1;
+                }
+                // Numbers, `%hi`, `\param` and `$reg` aren't names
+                let before = start.checked_sub(1).map(|before| bytes[before]);
+                if !bytes[start].is_ascii_digit() && !matches!(before, Some(b'%' | b'\\' | b'$')) {
+                    found.push((start, &expr[start..i]));
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    found.into_iter()
+}
+
+/// The mnemonics of `OpCode`, `MathFunc` and `RegImm`
+fn completions() -> Vec<Json> {
+    let item = |label: String, detail: String| {
+        Json::object([
+            ("label", label.into()),
+            ("kind", KEYWORD.into()),
+            ("detail", detail.into()),
+        ])
+    };
+    let opcodes = OpCode::iter()
+        .filter(|&op| op != OpCode::MATH && op != OpCode::REGIMM)
+        .map(|op| item(op.to_string(), format!("opcode {:#04x}", op as u8)))
+        // `beq`'s original syntax, which most code uses
+        .chain([item(
+            "beqz".to_owned(),
+            format!("opcode {:#04x}", OpCode::BEQ as u8),
+        )]);
+    let funcs = MathFunc::iter()
+        .map(|func| item(func.to_string(), format!("math, func {:#04x}", func as u8)));
+    let branches = RegImm::iter().map(|regimm| {
+        item(
+            regimm.to_string(),
+            format!("regimm, rt {:#04x}", regimm as u8),
+        )
+    });
+    opcodes.chain(funcs).chain(branches).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send each message in turn, and collect everything the server sends back
+    fn session(messages: &[Json]) -> Vec<Json> {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        assert!(run(&input[..], &mut output).unwrap());
+        let mut output = &output[..];
+        std::iter::from_fn(|| read_message(&mut output).unwrap())
+            .map(|message| Json::parse(&message).unwrap())
+            .collect()
+    }
+
+    fn request(id: usize, method: &str, params: Json) -> Json {
+        Json::object([
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ])
+    }
+
+    fn at(line: usize, character: usize) -> Json {
+        Json::object([
+            (
+                "textDocument",
+                Json::object([("uri", "file:///prog.mips".into())]),
+            ),
+            (
+                "position",
+                Json::object([("line", line.into()), ("character", character.into())]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_session() {
+        let open = |text: &str| {
+            request(
+                0,
+                "textDocument/didOpen",
+                Json::object([(
+                    "textDocument",
+                    Json::object([("uri", "file:///prog.mips".into()), ("text", text.into())]),
+                )]),
+            )
+        };
+        let responses = session(&[
+            request(1, "initialize", Json::object([])),
+            open("\tad\t1\t2\t3\n"),
+            open("loop\tlw\t2\t0\tnum\n\tbeqz\t0\t2\tloop\n\thalt\nnum\t.fill\tloop+4\n"),
+            request(2, "textDocument/definition", at(3, 12)),
+            request(3, "textDocument/references", at(0, 2)),
+            request(4, "textDocument/hover", at(1, 1)),
+            request(5, "textDocument/completion", Json::object([])),
+            request(6, "shutdown", Json::Null),
+            Json::object([("method", "exit".into())]),
+        ]);
+        assert_eq!(responses.len(), 8);
+        assert!(
+            responses[0]
+                .get("result")
+                .get("capabilities")
+                .get("hoverProvider")
+                == &Json::Bool(true)
+        );
+
+        let diags = responses[1].get("params").get("diagnostics").as_array();
+        assert_eq!(
+            diags[0].get("message").as_str(),
+            Some("unknown mnemonic `ad`")
+        );
+        assert_eq!(
+            diags[0].get("range").get("end").get("character").as_usize(),
+            Some(3)
+        );
+        // Lints once it assembles
+        let diags = responses[2].get("params").get("diagnostics").as_array();
+        assert_eq!(diags[0].get("severity").as_usize(), Some(3));
+
+        let range = |location: &Json| {
+            let start = location.get("range").get("start");
+            (
+                start.get("line").as_usize().unwrap(),
+                start.get("character").as_usize().unwrap(),
+            )
+        };
+        assert_eq!(range(responses[3].get("result")), (0, 0));
+        let references: Vec<_> = responses[4]
+            .get("result")
+            .as_array()
+            .iter()
+            .map(range)
+            .collect();
+        assert_eq!(references, [(0, 0), (1, 10), (3, 10)]);
+        assert_eq!(
+            responses[5]
+                .get("result")
+                .get("contents")
+                .get("value")
+                .as_str(),
+            Some("```\n00000004  1040fff8  beqz 0 2 -8\n```")
+        );
+        let completions = responses[6].get("result").as_array();
+        assert!(completions
+            .iter()
+            .any(|item| item.get("label").as_str() == Some("beqz")));
+        assert!(completions
+            .iter()
+            .any(|item| item.get("label").as_str() == Some("sub")));
+        assert!(!completions
+            .iter()
+            .any(|item| item.get("label").as_str() == Some("math")));
+        assert_eq!(responses[7].get("result"), &Json::Null);
+    }
+}
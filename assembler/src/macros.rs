@@ -0,0 +1,728 @@
+//! Expands includes, macros and repeats before assembly
+//!
+//! ```text
+//!         .macro  inc reg, by
+//!         addi    \reg    \reg    \by
+//!         .endm
+//!         inc     2, 1            r2 <- r2 + 1
+//!         .rept   10
+//!         inc     3, -1
+//!         .endr
+//! ```
+//!
+//! * `\param` is replaced by the argument. Like instructions, a macro only takes as many fields as it has
+//!   parameters, the rest are comments
+//! * Labels defined in a macro's body are local to each expansion, they're renamed to `label.macro.N`
+//! * `\@` is a number unique to each expansion, and each repetition of a `.rept`
+//! * The count of a `.rept` is a constant expression, which can use `.equ`s defined before it, but not labels. All
+//!   together, repetitions can't expand to more lines than there are words in memory
+//!
+//! Macros have to be defined before they're used, and can use other macros.
+//!
+//! `.include "file"` is replaced by the lines of the file, which is relative to the file that includes it. Errors in
+//! them are reported at the `.include`.
+
+use crate::data::MAX_BYTES;
+use crate::diag::{Diagnostic, Diagnostics, Span};
+use crate::expr;
+use crate::lexer::{Line, Token};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+/// Expansions nested deeper than this are assumed to be recursive
+const MAX_DEPTH: usize = 64;
+
+/// More repetitions or lines than there are words in memory can't be meant, and would take forever
+const MAX_EXPANDED: usize = MAX_BYTES as usize / 4;
+
+/// Where a line of the expanded source came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// A line of the original source, unchanged
+    Source(usize),
+    /// Generated by the macro or `.rept` named `name`, used at `at`
+    Expansion { at: Span, name: String },
+    /// Line `line` of the file at `path`, included at `at`
+    Include { at: Span, path: String, line: usize },
+}
+
+impl Origin {
+    /// Where in the original source the line came from
+    fn at(&self) -> Option<Span> {
+        match self {
+            Origin::Source(_) => None,
+            Origin::Expansion { at, .. } | Origin::Include { at, .. } => Some(*at),
+        }
+    }
+}
+
+/// The source with every include, macro and `.rept` expanded
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Expanded {
+    pub lines: Vec<String>,
+    pub origins: Vec<Origin>,
+}
+
+impl Expanded {
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// The line of the original source that expanded line `line` came from
+    pub fn source_line(&self, line: usize) -> usize {
+        match &self.origins[line] {
+            Origin::Source(line) => *line,
+            origin => origin.at().unwrap().line,
+        }
+    }
+
+    /// Move a diagnostic about the expanded source to the original source
+    pub fn locate(&self, mut diag: Diagnostic) -> Diagnostic {
+        match self.origins.get(diag.span.line) {
+            Some(Origin::Source(line)) => diag.span.line = *line,
+            Some(origin @ Origin::Expansion { .. }) | Some(origin @ Origin::Include { .. }) => {
+                let text = self.lines[diag.span.line].trim();
+                let note = match origin {
+                    Origin::Include { path, line, .. } => {
+                        format!("in `{}` line {}: `{}`", path, line + 1, text)
+                    }
+                    Origin::Expansion { name, .. } => {
+                        format!("in this expansion of `{}`: `{}`", name, text)
+                    }
+                    Origin::Source(_) => unreachable!(),
+                };
+                diag.span = origin.at().unwrap();
+                diag.notes.insert(0, note);
+            }
+            None => {}
+        }
+        diag
+    }
+}
+
+/// Expand every include, macro and `.rept` in `source`. `include` reads a file, relative to `source`'s directory, or
+/// gives an error message
+pub fn expand(
+    source: &str,
+    include: &mut dyn FnMut(&str) -> Result<String, String>,
+    diags: &mut Diagnostics,
+) -> Expanded {
+    let items = source
+        .lines()
+        .enumerate()
+        .map(|(line, text)| Item {
+            text: text.to_owned(),
+            origin: Origin::Source(line),
+        })
+        .collect();
+    let mut expander = Expander {
+        include,
+        macros: HashMap::new(),
+        equs: HashMap::new(),
+        count: 0,
+        dirs: Vec::new(),
+        too_big: false,
+        out: Expanded::default(),
+    };
+    expander.process(items, 0, diags);
+    expander.out
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Item>,
+    /// Labels defined in the body
+    locals: Vec<String>,
+}
+
+/// A line waiting to be expanded
+#[derive(Clone)]
+struct Item {
+    text: String,
+    origin: Origin,
+}
+
+impl Item {
+    /// Where to report a problem with `tok`, which is from this line
+    fn span(&self, tok: Token) -> Span {
+        self.origin.at().unwrap_or(tok.span)
+    }
+
+    fn line_num(&self) -> usize {
+        match &self.origin {
+            Origin::Source(line) => *line,
+            origin => origin.at().unwrap().line,
+        }
+    }
+}
+
+struct Expander<'i> {
+    include: &'i mut dyn FnMut(&str) -> Result<String, String>,
+    macros: HashMap<String, Macro>,
+    /// `.equ`s seen so far, for `.rept` counts
+    equs: HashMap<String, String>,
+    /// Expansions so far, for `\@` and local labels
+    count: usize,
+    /// The directories of the files being included, innermost last. Their includes are relative to them
+    dirs: Vec<PathBuf>,
+    /// Whether a `.rept` has expanded to too much, after which nothing more is repeated
+    too_big: bool,
+    out: Expanded,
+}
+
+impl Expander<'_> {
+    fn process(&mut self, items: Vec<Item>, depth: usize, diags: &mut Diagnostics) {
+        let mut i = 0;
+        while i < items.len() {
+            let item = &items[i];
+            let line = Line::new(item.line_num(), &item.text);
+            let op = match line.op {
+                Some(op) => op,
+                None => {
+                    self.emit(item.clone());
+                    i += 1;
+                    continue;
+                }
+            };
+            match op.text {
+                ".macro" | ".rept" => {
+                    let (start, end) = if op.text == ".macro" {
+                        (".macro", ".endm")
+                    } else {
+                        (".rept", ".endr")
+                    };
+                    let body_end = match find_end(&items[i + 1..], start, end) {
+                        Some(len) => i + 1 + len,
+                        None => {
+                            diags.error(item.span(op), format!("`{}` without an `{}`", start, end));
+                            items.len()
+                        }
+                    };
+                    let body = items[i + 1..body_end].to_vec();
+                    if op.text == ".macro" {
+                        self.define(item, &line, body, diags);
+                    } else if let Some(count) = self.rept_count(item, &line, op, diags) {
+                        let origin = match &item.origin {
+                            Origin::Source(_) => None,
+                            origin => Some(origin.clone()),
+                        };
+                        for _ in 0..count {
+                            if self.too_big
+                                || self.count >= MAX_EXPANDED
+                                || self.out.lines.len() >= MAX_EXPANDED
+                            {
+                                if !self.too_big {
+                                    diags.push(
+                                        Diagnostic::error(
+                                            item.span(op),
+                                            "`.rept` expands to too much",
+                                        )
+                                        .with_note(
+                                            format!(
+                                                "there can only be {} repetitions and lines in all",
+                                                MAX_EXPANDED
+                                            ),
+                                        ),
+                                    );
+                                    self.too_big = true;
+                                }
+                                break;
+                            }
+                            self.count += 1;
+                            let body = body
+                                .iter()
+                                .map(|body_item| {
+                                    let text =
+                                        body_item.text.replace("\\@", &self.count.to_string());
+                                    let origin = if text == body_item.text {
+                                        body_item.origin.clone()
+                                    } else {
+                                        origin.clone().unwrap_or_else(|| Origin::Expansion {
+                                            at: op.span,
+                                            name: ".rept".to_owned(),
+                                        })
+                                    };
+                                    Item { text, origin }
+                                })
+                                .collect();
+                            if !self.descend(body, depth, item.span(op), ".rept", diags) {
+                                break;
+                            }
+                        }
+                    }
+                    // Skip the body and the end
+                    i = body_end + 1;
+                    continue;
+                }
+                ".endm" | ".endr" => {
+                    diags.error(item.span(op), format!("`{}` without a start", op.text));
+                }
+                ".include" => self.include(item, &line, op, depth, diags),
+                ".equ" => {
+                    if let (Some(name), Some(value)) = (line.operands.first(), line.operands.get(1))
+                    {
+                        self.equs
+                            .insert(name.text.to_owned(), value.text.to_owned());
+                    }
+                    self.emit(item.clone());
+                }
+                name if self.macros.contains_key(name) => {
+                    self.invoke(item, &line, op, depth, diags);
+                }
+                _ => self.emit(item.clone()),
+            }
+            i += 1;
+        }
+    }
+
+    fn emit(&mut self, item: Item) {
+        self.out.lines.push(item.text);
+        self.out.origins.push(item.origin);
+    }
+
+    /// Expand `items` one level deeper. `false` if it's too deep
+    fn descend(
+        &mut self,
+        items: Vec<Item>,
+        depth: usize,
+        at: Span,
+        name: &str,
+        diags: &mut Diagnostics,
+    ) -> bool {
+        if depth >= MAX_DEPTH {
+            diags.push(
+                Diagnostic::error(at, format!("`{}` is nested too deeply", name)).with_note(
+                    format!(
+                        "expansions can only be nested {} deep, is it recursive?",
+                        MAX_DEPTH
+                    ),
+                ),
+            );
+            return false;
+        }
+        self.process(items, depth + 1, diags);
+        true
+    }
+
+    /// `.include "file"`
+    fn include(
+        &mut self,
+        item: &Item,
+        line: &Line,
+        op: Token,
+        depth: usize,
+        diags: &mut Diagnostics,
+    ) {
+        let tok = match line.operands.first() {
+            Some(tok) => tok,
+            None => {
+                diags.error(
+                    item.span(op),
+                    "missing operand: `.include` expects a quoted file name here",
+                );
+                return;
+            }
+        };
+        let path = match tok
+            .text
+            .strip_prefix('"')
+            .and_then(|path| path.strip_suffix('"'))
+        {
+            Some(path) if tok.text.len() >= 2 => path,
+            _ => {
+                diags.error(
+                    item.span(*tok),
+                    format!("expected a quoted file name, found `{}`", tok.text),
+                );
+                return;
+            }
+        };
+        let dir = self.dirs.last().map_or(Path::new(""), |dir| dir.as_path());
+        let full = dir.join(path);
+        let source = match (self.include)(&full.to_string_lossy()) {
+            Ok(source) => source,
+            Err(err) => {
+                diags.error(
+                    item.span(*tok),
+                    format!("can't include `{}`: {}", path, err),
+                );
+                return;
+            }
+        };
+        let at = item.span(*tok);
+        let items = source
+            .lines()
+            .enumerate()
+            .map(|(line, text)| Item {
+                text: text.to_owned(),
+                origin: match &item.origin {
+                    // Errors in a macro are already reported where it's used
+                    origin @ Origin::Expansion { .. } => origin.clone(),
+                    _ => Origin::Include {
+                        at,
+                        path: full.to_string_lossy().into_owned(),
+                        line,
+                    },
+                },
+            })
+            .collect();
+        self.dirs
+            .push(full.parent().map_or_else(PathBuf::new, Path::to_path_buf));
+        self.descend(items, depth, at, path, diags);
+        self.dirs.pop();
+    }
+
+    /// `.macro name params...`
+    fn define(&mut self, item: &Item, line: &Line, body: Vec<Item>, diags: &mut Diagnostics) {
+        let op = line.op.unwrap();
+        let name = match line.operands.first() {
+            Some(name) => name,
+            None => {
+                diags.error(
+                    item.span(op),
+                    "missing operand: `.macro` expects a name here",
+                );
+                return;
+            }
+        };
+        if crate::asm::is_mnemonic(name.text) {
+            diags.error(
+                item.span(*name),
+                format!("`{}` is already an instruction or directive", name.text),
+            );
+            return;
+        }
+        let params: Vec<String> = line.operands[1..]
+            .iter()
+            .map(|param| param.text.to_owned())
+            .collect();
+        let locals = body
+            .iter()
+            .filter_map(|body_item| Line::new(0, &body_item.text).label)
+            .filter(|label| !label.text.contains('\\'))
+            .map(|label| label.text.to_owned())
+            .collect();
+        self.macros.insert(
+            name.text.to_owned(),
+            Macro {
+                params,
+                body,
+                locals,
+            },
+        );
+    }
+
+    /// The count of a `.rept`
+    fn rept_count(
+        &self,
+        item: &Item,
+        line: &Line,
+        op: Token,
+        diags: &mut Diagnostics,
+    ) -> Option<u32> {
+        let tok = match line.operands.first() {
+            Some(tok) => tok,
+            None => {
+                diags.error(
+                    item.span(op),
+                    "missing operand: `.rept` expects a count here",
+                );
+                return None;
+            }
+        };
+        let result = expr::eval(tok.text, &mut |name| resolve_equ(name, &self.equs, 0));
+        match result.map(u32::try_from) {
+            Ok(Ok(count)) if count as usize <= MAX_EXPANDED => Some(count),
+            Ok(Ok(_)) => {
+                diags.push(
+                    Diagnostic::error(
+                        item.span(*tok),
+                        format!("`.rept {}` repeats too many times", tok.text),
+                    )
+                    .with_note(format!("it can repeat at most {} times", MAX_EXPANDED)),
+                );
+                None
+            }
+            Ok(Err(_)) => {
+                diags.error(
+                    item.span(*tok),
+                    format!("`{}` isn't a valid count", tok.text),
+                );
+                None
+            }
+            Err(err) => {
+                let span = item.origin.at().unwrap_or_else(|| {
+                    Span::new(tok.span.line, tok.span.col + err.col, err.len.max(1))
+                });
+                diags.error(span, err.message);
+                None
+            }
+        }
+    }
+
+    /// Replace the macro `op` on `line` with its body
+    fn invoke(
+        &mut self,
+        item: &Item,
+        line: &Line,
+        op: Token,
+        depth: usize,
+        diags: &mut Diagnostics,
+    ) {
+        let mac = &self.macros[op.text];
+        if line.operands.len() < mac.params.len() {
+            diags.error(
+                item.span(op),
+                format!(
+                    "`{}` expects {} argument{}, found {}",
+                    op.text,
+                    mac.params.len(),
+                    if mac.params.len() == 1 { "" } else { "s" },
+                    line.operands.len()
+                ),
+            );
+            return;
+        }
+        self.count += 1;
+        let origin = match &item.origin {
+            Origin::Source(_) => Origin::Expansion {
+                at: op.span,
+                name: op.text.to_owned(),
+            },
+            // Report everything at the outermost use
+            origin => origin.clone(),
+        };
+
+        let mut items = Vec::new();
+        if let Some(label) = line.label {
+            items.push(Item {
+                text: format!("{}:", label.text),
+                origin: origin.clone(),
+            });
+        }
+        let args: Vec<&str> = line.operands.iter().map(|arg| arg.text).collect();
+        for body_item in &mac.body {
+            items.push(Item {
+                text: substitute(
+                    &body_item.text,
+                    &mac.params,
+                    &args,
+                    &mac.locals,
+                    op.text,
+                    self.count,
+                ),
+                origin: origin.clone(),
+            });
+        }
+        self.descend(items, depth, item.span(op), op.text, diags);
+    }
+}
+
+/// How many items there are before the `end` that matches a `start` that was just passed
+fn find_end(items: &[Item], start: &str, end: &str) -> Option<usize> {
+    let mut nesting = 0;
+    for (i, item) in items.iter().enumerate() {
+        match Line::new(0, &item.text).op.map(|op| op.text) {
+            Some(op) if op == start => nesting += 1,
+            Some(op) if op == end => {
+                if nesting == 0 {
+                    return Some(i);
+                }
+                nesting -= 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn resolve_equ(name: &str, equs: &HashMap<String, String>, depth: usize) -> Result<i64, String> {
+    match equs.get(name) {
+        Some(_) if depth >= MAX_DEPTH => Err(format!("`{}` is defined in terms of itself", name)),
+        Some(value) => expr::eval(value, &mut |name| resolve_equ(name, equs, depth + 1))
+            .map_err(|err| format!("`{}` can't be evaluated: {}", name, err)),
+        None => Err(format!("`{}` has to be a `.equ` defined before here", name)),
+    }
+}
+
+/// Fill in one line of a macro's body
+fn substitute(
+    text: &str,
+    params: &[String],
+    args: &[&str],
+    locals: &[String],
+    name: &str,
+    count: usize,
+) -> String {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut quoted = false;
+    while let Some(c) = rest.chars().next() {
+        if c == '\\' {
+            let after = &rest[1..];
+            if let Some(after) = after.strip_prefix('@') {
+                out.push_str(&count.to_string());
+                rest = after;
+                continue;
+            }
+            let len = after.find(|c| !is_ident(c)).unwrap_or(after.len());
+            if let Some(idx) = params.iter().position(|param| param == &after[..len]) {
+                out.push_str(args[idx]);
+                rest = &after[len..];
+                continue;
+            }
+            // Not a parameter, like an escape in a string
+            out.push(c);
+            out.push_str(&after[..after.chars().next().map_or(0, char::len_utf8)]);
+            rest = &after[after.chars().next().map_or(0, char::len_utf8)..];
+        } else if c == '"' {
+            quoted = !quoted;
+            out.push(c);
+            rest = &rest[1..];
+        } else if is_ident(c) && !quoted {
+            let len = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+            let ident = &rest[..len];
+            if locals.iter().any(|local| local == ident) {
+                out.push_str(&format!("{}.{}.{}", ident, name, count));
+            } else {
+                out.push_str(ident);
+            }
+            rest = &rest[len..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Only `inc.mips`, and `sub/a.mips` which includes `sub/b.mips`, can be included
+    fn include(path: &str) -> Result<String, String> {
+        match path {
+            "inc.mips" => Ok("\t.macro\tinc reg\n\taddi\t\\reg\t\\reg\t1\n\t.endm\n".to_owned()),
+            "sub/a.mips" => Ok("\t.include\t\"b.mips\"\n\taddi\t1\t1\t1\n".to_owned()),
+            "sub/b.mips" => Ok("\taddi\t2\t2\t2\n".to_owned()),
+            _ => Err("No such file or directory".to_owned()),
+        }
+    }
+
+    fn expand_ok(source: &str) -> Vec<String> {
+        let mut diags = Diagnostics::default();
+        let expanded = expand(source, &mut include, &mut diags);
+        assert!(diags.is_empty(), "{:?}", diags);
+        expanded.lines
+    }
+
+    #[test]
+    fn test_expand() {
+        let source = "\
+\t.include\t\"inc.mips\"
+\t.macro\tcountdown reg
+top\taddi\t\\reg\t\\reg\t-1
+\tbeqz\t0\t\\reg\ttop
+\t.endm
+start\tcountdown\t3\tcomment
+\t.rept\t2
+\tcountdown\t$t\\@
+\t.endr
+\tinc\t5
+";
+        assert_eq!(
+            expand_ok(source),
+            [
+                "start:",
+                "top.countdown.1\taddi\t3\t3\t-1",
+                "\tbeqz\t0\t3\ttop.countdown.1",
+                "top.countdown.3\taddi\t$t2\t$t2\t-1",
+                "\tbeqz\t0\t$t2\ttop.countdown.3",
+                "top.countdown.5\taddi\t$t4\t$t4\t-1",
+                "\tbeqz\t0\t$t4\ttop.countdown.5",
+                "\taddi\t5\t5\t1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_include() {
+        // `b.mips` is next to `sub/a.mips`, which includes it
+        let mut diags = Diagnostics::default();
+        let expanded = expand("\t.include\t\"sub/a.mips\"\n", &mut include, &mut diags);
+        assert!(diags.is_empty(), "{:?}", diags);
+        assert_eq!(expanded.lines, ["\taddi\t2\t2\t2", "\taddi\t1\t1\t1"]);
+        assert!(
+            matches!(&expanded.origins[0], Origin::Include { path, line: 0, .. } if path == "sub/b.mips")
+        );
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "\
+\t.macro\tpush reg
+\taddi\t$sp\t$sp\t-4
+\tsw\t\\reg\t$sp\t0
+\t.endm
+\tpush\t2
+\t.rept\t2
+\tnop
+\t.endr
+end\thalt
+";
+        let program = crate::assemble(source).unwrap();
+        assert_eq!(program.symbols["end"], 16);
+        assert_eq!(program.source_map, [4, 4, 6, 6, 8]);
+
+        let diags = crate::assemble(&format!("{}\tpush\t40\n", source)).unwrap_err();
+        let diag = diags.iter().next().unwrap();
+        assert_eq!(diag.message, "register `40` is out of range (0..31)");
+        assert_eq!(diag.span, Span::new(9, 1, 4));
+        assert_eq!(
+            diag.notes[0],
+            "in this expansion of `push`: `sw\t40\t$sp\t0`"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut diags = Diagnostics::default();
+        expand(
+            "\t.macro\tforever\n\tforever\n\t.endm\n\tforever\n\t.rept\t-1\n\t.endr\n\t.endr\n\t.include\t\"x\"\n",
+            &mut include,
+            &mut diags,
+        );
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`forever` is nested too deeply",
+                "`-1` isn't a valid count",
+                "`.endr` without a start",
+                "can't include `x`: No such file or directory"
+            ]
+        );
+        assert_eq!(diags.iter().next().unwrap().span, Span::new(3, 1, 7));
+
+        // Too many repetitions, alone or nested, are reported once, at the `.rept`
+        let mut diags = Diagnostics::default();
+        expand(
+            "\t.rept\t0xffffffff\n\tnop\n\t.endr\n\t.rept\t0x1000\n\t.rept\t0x1000\n\t.endr\n\t.endr\n",
+            &mut include,
+            &mut diags,
+        );
+        let messages: Vec<_> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`.rept 0xffffffff` repeats too many times",
+                "`.rept` expands to too much"
+            ]
+        );
+        assert_eq!(diags.iter().last().unwrap().span, Span::new(4, 1, 5));
+    }
+}
@@ -1,15 +1,13 @@
-use assembler::instr::{Instruction, MathFunc, OpCode};
+use assembler::assemble_path;
+use assembler::format::Format;
+use assembler::mem::Endian;
+use assembler::object::write_object;
+use assembler::schedule::{schedule, Schedule};
 
-use std::convert::TryFrom;
-use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufRead, BufReader, BufWriter, Write},
-    path::PathBuf,
-};
+use std::{fs, path::Path, path::PathBuf};
 
-use anyhow::Result;
-use argh::FromArgs;
+use anyhow::{bail, Result};
+use argh::{FromArgs, TopLevelCommand};
 
 /// error: usage: %s <assembly-code-file> <machine-code-file>
 #[derive(FromArgs)]
@@ -20,102 +18,87 @@ struct Args {
     /// the assembled machine code file
     #[argh(option, short = 'o')]
     output: PathBuf,
+    /// write an object file for `link` instead of machine code
+    #[argh(switch, short = 'c')]
+    object: bool,
+    /// the machine code format: hex (the default), bin-le, bin-be, ihex, logisim, memh, c or rust
+    #[argh(option, default = "Format::default()")]
+    format: Format,
+    /// also write a listing of each line's address, words and disassembly, and the symbols
+    #[argh(option)]
+    listing: Option<PathBuf>,
+    /// for a pipeline without forwarding or hazard detection: `nops` inserts nops, `reorder` also reorders
+    /// instructions within basic blocks
+    #[argh(option)]
+    schedule: Option<Schedule>,
+    /// pack strings for a `little` (the default) or `big` endian memory, like the simulators' `--endian`
+    #[argh(option, default = "Endian::default()")]
+    endian: Endian,
 }
 
-type Labels = HashMap<String, u16>;
-
-fn main() -> Result<()> {
-    let Args {
-        input: input_path,
-        output,
-    } = argh::from_env::<Args>();
-    let input = File::open(&input_path)?;
-    let output = File::create(output)?;
-    let mut output = BufWriter::new(output);
-
-    // First Pass
-    let labels = get_labels(&input);
-    let input = File::open(&input_path)?;
-    write_instructions(&input, &mut output, &labels)?;
-    Ok(())
-}
-
-fn get_labels(input: &File) -> Labels {
-    let input = BufReader::new(input);
-    input
-        .lines()
-        .map(|it| it.unwrap())
-        .enumerate()
-        .filter_map(|(line_num, line)| {
-            let (label, _opcode, _toks) = parse_label_opcode(&line);
-             if !label.is_empty() {
-                Some((
-                    label.to_owned(),
-                    (line_num * 4) as u16, // narrowing conversion
-                ))
-            } else {
-                None
+/// Like `argh::from_env`, but also accepting `--option=value`
+fn from_env<T: TopLevelCommand>() -> T {
+    let strings: Vec<String> = std::env::args()
+        .flat_map(|arg| match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                vec![name.to_owned(), value.to_owned()]
             }
+            _ => vec![arg],
         })
-        .collect()
-}
-
-fn parse_label_opcode(line: &str) -> (&str, &str, impl Iterator<Item = &str>) {
-    let mut toks = line.split('\t');
-    (toks.next().unwrap(), toks.next().unwrap(), toks)
+        .collect();
+    let strs: Vec<&str> = strings.iter().map(String::as_str).collect();
+    let cmd = Path::new(strs[0])
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(strs[0]);
+    T::from_args(&[cmd], &strs[1..]).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!("{}", early_exit.output);
+                1
+            }
+        })
+    })
 }
 
-fn write_instructions(input: &File, output: &mut BufWriter<File>, labels: &Labels) -> Result<()> {
-    let input = BufReader::new(input);
-    for (line_num, line) in input.lines().enumerate() {
-        let line = &(line.unwrap());
-        let (_label, op, mut toks) = parse_label_opcode(line);
-        let instr = if let Ok(func) = op.parse::<MathFunc>() {
-            let a0 = toks.next().unwrap().parse().unwrap();
-            let a1 = toks.next().unwrap().parse().unwrap();
-            let a2 = toks.next().unwrap().parse().unwrap();
-            Instruction::math(func, (a0, a1, a2))
-        } else if let Ok(op) = op.parse::<OpCode>() {
-            match op {
-                OpCode::ADDI | OpCode::LW | OpCode::SW | OpCode::BEQZ => {
-                    let a0 = toks.next().unwrap().parse().unwrap();
-                    let a1 = toks.next().unwrap().parse().unwrap();
-                    let imm = toks.next().unwrap();
-                    let imm = if let OpCode::BEQZ = op {
-                        imm.parse()
-                            .or_else(|_| {
-                                i16::try_from(labels[imm])
-                                    .map(|addr| addr - (line_num as i16) * 4 - 4)
-                            })
-                            .unwrap()
-                    } else {
-                        parse_imm(imm, labels)
-                    };
+fn main() -> Result<()> {
+    let Args {
+        input,
+        output,
+        object,
+        format,
+        listing,
+        schedule: mode,
+        endian,
+    } = from_env::<Args>();
+    if object && format != Format::Hex {
+        bail!("objects have their own format, `--format` can't be used with `-c`");
+    }
+    if object && mode.is_some() {
+        bail!("only whole programs can be scheduled, `--schedule` can't be used with `-c`");
+    }
+    let program = match mode {
+        // Every address has to be relocatable to move it
+        Some(mode) => schedule(&assemble_path(&input, true, endian)?, mode)?,
+        None => assemble_path(&input, object, endian)?,
+    };
 
-                    Instruction::i_type(op, (a0, a1, imm))
-                }
-                OpCode::JALR => {
-                    let offs_or_label = toks.next().unwrap();
-                    let offs = parse_imm(offs_or_label, labels);
-                    Instruction::jalr(offs)
-                }
-                OpCode::HALT => {
-                    Instruction::halt()
-                }
-                OpCode::MATH => panic!("MATH is not a assembly instruction. Parsing was already handled for math instructions"),
-            }
-        } else if op == ".fill" {
-            let fill: i32 = toks.next().unwrap().parse().unwrap();
-            (fill as u32).into()
-        } else {
-            panic!("unrecognized opcode {} at line {}", op, line_num + 1)
-        };
-        let instr = u32::from(instr);
-        writeln!(output, "{:08x}", instr)?;
+    // Only create the outputs once we know they're complete
+    let mut words = Vec::new();
+    if object {
+        write_object(&program, &mut words)?;
+    } else {
+        format.write(&program.words, &mut words)?;
+    }
+    fs::write(output, words)?;
+    if let Some(listing) = listing {
+        let mut lines = Vec::new();
+        program.write_listing(&fs::read_to_string(&input)?, &mut lines)?;
+        fs::write(listing, lines)?;
     }
     Ok(())
 }
-
-fn parse_imm(imm: &str, labels: &Labels) -> i16 {
-    imm.parse().or_else(|_| i16::try_from(labels[imm])).unwrap()
-}
@@ -0,0 +1,131 @@
+//! Byte addressable memory, for the simulators
+//!
+//! Memory is kept as words, like the assembler writes them, and byte `addr` is one of the bytes of word `addr / 4`.
+//! Which one depends on the [`Endian`]ness: little endian keeps byte 0 in the low 8 bits of a word, big endian in the
+//! high 8 bits. Words read the same either way. An access has to be aligned to its width and inside memory, or it's
+//! an [`AddressError`].
+
+use std::fmt::Display;
+
+use strum_macros::{Display, EnumString};
+
+/// How many bytes a load or store accesses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+}
+
+#[derive(EnumString, Display, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Why an access can't be made, with the address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// The address isn't a multiple of the width
+    Unaligned(u32),
+    /// The address is past the end of memory
+    OutOfRange(u32),
+}
+
+impl Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressError::Unaligned(addr) => write!(f, "unaligned address {:#x}", addr),
+            AddressError::OutOfRange(addr) => write!(f, "address {:#x} is out of range", addr),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+impl AddressError {
+    pub fn addr(self) -> u32 {
+        match self {
+            AddressError::Unaligned(addr) | AddressError::OutOfRange(addr) => addr,
+        }
+    }
+}
+
+/// The word holding `addr`, and how far its `width` bytes are shifted up in it
+fn locate(
+    len: usize,
+    addr: u32,
+    width: Width,
+    endian: Endian,
+) -> Result<(usize, u32), AddressError> {
+    let width = width as u32;
+    if addr % width != 0 {
+        return Err(AddressError::Unaligned(addr));
+    }
+    let index = (addr / 4) as usize;
+    if index >= len {
+        return Err(AddressError::OutOfRange(addr));
+    }
+    let offset = addr % 4;
+    let shift = match endian {
+        Endian::Little => 8 * offset,
+        Endian::Big => 8 * (4 - width - offset),
+    };
+    Ok((index, shift))
+}
+
+fn mask(width: Width) -> u32 {
+    u32::MAX >> (32 - 8 * width as u32)
+}
+
+/// Read the `width` bytes at `addr`, zero extended
+pub fn load(memory: &[u32], addr: u32, width: Width, endian: Endian) -> Result<u32, AddressError> {
+    let (index, shift) = locate(memory.len(), addr, width, endian)?;
+    Ok(memory[index] >> shift & mask(width))
+}
+
+/// Write the low `width` bytes of `value` to `addr`
+pub fn store(
+    memory: &mut [u32],
+    addr: u32,
+    width: Width,
+    value: u32,
+    endian: Endian,
+) -> Result<(), AddressError> {
+    let (index, shift) = locate(memory.len(), addr, width, endian)?;
+    let mask = mask(width) << shift;
+    memory[index] = memory[index] & !mask | value << shift & mask;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory() {
+        let mut memory = [0x1122_3344, 0];
+        assert_eq!(load(&memory, 1, Width::Byte, Endian::Little), Ok(0x33));
+        assert_eq!(load(&memory, 1, Width::Byte, Endian::Big), Ok(0x22));
+        assert_eq!(load(&memory, 2, Width::Half, Endian::Little), Ok(0x1122));
+        assert_eq!(load(&memory, 2, Width::Half, Endian::Big), Ok(0x3344));
+        assert_eq!(load(&memory, 0, Width::Word, Endian::Big), Ok(0x1122_3344));
+
+        store(&mut memory, 7, Width::Byte, 0x1ff, Endian::Little).unwrap();
+        store(&mut memory, 6, Width::Half, 0xabcd, Endian::Big).unwrap();
+        assert_eq!(memory[1], 0xff00_abcd);
+        store(&mut memory, 4, Width::Byte, 0x7f, Endian::Big).unwrap();
+        assert_eq!(memory[1], 0x7f00_abcd);
+
+        assert_eq!(
+            load(&memory, 2, Width::Word, Endian::Little),
+            Err(AddressError::Unaligned(2))
+        );
+        assert_eq!(
+            store(&mut memory, 8, Width::Byte, 0, Endian::Little),
+            Err(AddressError::OutOfRange(8))
+        );
+    }
+}
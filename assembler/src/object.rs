@@ -0,0 +1,285 @@
+//! Object files, and linking them into one program
+//!
+//! `assembler -c` makes an object instead of machine code. Labels exported with `.globl name` can be used by other
+//! objects, which declare them with `.extern name`. Anywhere an address is used is recorded as a relocation, so the
+//! linker can fix it once it knows where each object goes.
+//!
+//! An object file is text:
+//!
+//! ```text
+//! export  print 0000000c
+//! reloc   00000004 abs16 print
+//! reloc   00000010 abs32
+//! 2003000c
+//! ...
+//! ```
+//!
+//! * `export name addr` is a `.globl` label and its address in the object
+//! * `reloc addr kind [name]` adds the address of `name`, or of the object itself if there's no name, to the word at
+//!   `addr`. `abs16` adds it to the immediate, `rel16` adds it minus the object's address (branches), `abs26` adds it
+//!   to the word address of a `j` or `jal`, and `abs32` adds it to the whole word (`.fill`/`.word`)
+//! * The words follow, one `{:08x}` per line
+//!
+//! `link` puts the objects one after another in the order they're given, so the first one is where execution starts.
+
+use crate::asm::Program;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+use strum_macros::{Display, EnumString};
+
+#[derive(EnumString, Display, Clone, Copy, Debug, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum RelocKind {
+    /// The 16 bit immediate of `addi`, `lw` or `sw`
+    Abs16,
+    /// The 16 bit offset of a branch
+    Rel16,
+    /// The 26 bit word address of `j` or `jal`
+    Abs26,
+    /// A whole data word
+    Abs32,
+}
+
+/// A word that needs an address added to it when it's linked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    /// Byte address of the word in its object
+    pub addr: u32,
+    pub kind: RelocKind,
+    /// The `.extern` whose address is added, or `None` for the address of the object itself
+    pub symbol: Option<String>,
+}
+
+/// Write `program` as an object file
+pub fn write_object(program: &Program, mut output: impl Write) -> io::Result<()> {
+    for name in &program.exports {
+        writeln!(output, "export\t{}\t{:08x}", name, program.symbols[name])?;
+    }
+    for reloc in &program.relocations {
+        write!(output, "reloc\t{:08x}\t{}", reloc.addr, reloc.kind)?;
+        if let Some(symbol) = &reloc.symbol {
+            write!(output, "\t{}", symbol)?;
+        }
+        writeln!(output)?;
+    }
+    program.write_hex(output)
+}
+
+/// Read an object file. The program's symbols are its exports
+pub fn read_object(input: impl BufRead) -> Result<Program> {
+    let mut program = Program::default();
+    for (line_num, line) in input.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields.as_slice() {
+            [] => Some(()),
+            ["export", name, addr] => u32::from_str_radix(addr, 16).ok().map(|addr| {
+                program.symbols.insert((*name).to_owned(), addr);
+                program.exports.insert((*name).to_owned());
+            }),
+            ["reloc", addr, kind, symbol @ ..] if symbol.len() <= 1 => {
+                u32::from_str_radix(addr, 16)
+                    .ok()
+                    .zip(kind.parse().ok())
+                    .map(|(addr, kind)| {
+                        program.relocations.push(Relocation {
+                            addr,
+                            kind,
+                            symbol: symbol.first().map(|&symbol| symbol.to_owned()),
+                        })
+                    })
+            }
+            [word] => u32::from_str_radix(word, 16)
+                .ok()
+                .map(|word| program.words.push(word)),
+            _ => None,
+        };
+        if parsed.is_none() {
+            bail!(
+                "line {}: expected an export, relocation or hex word, found `{}`",
+                line_num + 1,
+                line
+            );
+        }
+    }
+    Ok(program)
+}
+
+/// Link objects, named for errors, into one program. Its symbols are every export
+pub fn link(objects: &[(String, Program)]) -> Result<Program> {
+    let mut linked = Program::default();
+    let mut errors = String::new();
+    let mut bases = Vec::with_capacity(objects.len());
+    let mut defined_in = BTreeMap::new();
+    for (name, object) in objects {
+        let base = 4 * linked.words.len() as u32;
+        bases.push(base);
+        linked.words.extend(&object.words);
+        for export in &object.exports {
+            if let Some(first) = defined_in.insert(export, name) {
+                let _ = writeln!(
+                    errors,
+                    "`{}` is exported by both {} and {}",
+                    export, first, name
+                );
+            }
+            linked
+                .symbols
+                .insert(export.clone(), base + object.symbols[export]);
+        }
+    }
+
+    for ((name, object), &base) in objects.iter().zip(&bases) {
+        for reloc in &object.relocations {
+            let target = match &reloc.symbol {
+                None => base,
+                Some(symbol) => match linked.symbols.get(symbol) {
+                    Some(&addr) => addr,
+                    None => {
+                        let _ = writeln!(
+                            errors,
+                            "{} uses `{}`, which no object exports",
+                            name, symbol
+                        );
+                        continue;
+                    }
+                },
+            };
+            let word = match linked.words.get_mut(((base + reloc.addr) / 4) as usize) {
+                Some(word) if reloc.addr % 4 == 0 && reloc.addr / 4 < object.words.len() as u32 => {
+                    word
+                }
+                _ => {
+                    let _ = writeln!(
+                        errors,
+                        "{} has a relocation at {:#x}, outside of it",
+                        name, reloc.addr
+                    );
+                    continue;
+                }
+            };
+            if reloc.kind == RelocKind::Abs32 {
+                *word = word.wrapping_add(target);
+                continue;
+            }
+            if reloc.kind == RelocKind::Abs26 {
+                let addr = ((*word & 0x03ff_ffff) << 2).wrapping_add(target);
+                if addr >> 28 == 0 {
+                    *word = (*word & 0xfc00_0000) | addr >> 2;
+                } else {
+                    let _ = writeln!(
+                        errors,
+                        "{} at {:#x}: the jump target {:#x} isn't in the first 256MB once `{}` is at {:#x}",
+                        name,
+                        reloc.addr,
+                        addr,
+                        reloc.symbol.as_deref().unwrap_or(name),
+                        target
+                    );
+                }
+                continue;
+            }
+            let mut value = i64::from(*word as u16 as i16) + i64::from(target);
+            if reloc.kind == RelocKind::Rel16 {
+                value -= i64::from(base);
+            }
+            match i16::try_from(value) {
+                Ok(value) => *word = (*word & 0xffff_0000) | u32::from(value as u16),
+                Err(_) => {
+                    let _ = writeln!(
+                        errors,
+                        "{} at {:#x}: {} doesn't fit in 16 signed bits once `{}` is at {:#x}",
+                        name,
+                        reloc.addr,
+                        value,
+                        reloc.symbol.as_deref().unwrap_or(name),
+                        target
+                    );
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(linked)
+    } else {
+        bail!("{}", errors.trim_end())
+    }
+}
+
+/// Read the object file at `path`
+pub fn read_object_file(path: &std::path::Path) -> Result<Program> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    read_object(io::BufReader::new(file))
+        .with_context(|| format!("could not read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_object;
+
+    #[test]
+    fn test_link() {
+        let main = assemble_object(
+            "\t.extern\tdouble\n\tlw\t2\t0\tnum\n\taddi\t3\t0\tdouble\n\tjalr\t3\n\thalt\nnum\t.fill\t21\n",
+        )
+        .unwrap();
+        let lib = assemble_object(
+            "\t.globl\tdouble\nptr\t.fill\tdouble\ndouble\tadd\t2\t2\t2\n\tbeqz\t0\t0\tdone\ndone\tjalr\t0\t31\n",
+        )
+        .unwrap();
+
+        let mut file = Vec::new();
+        write_object(&lib, &mut file).unwrap();
+        assert_eq!(
+            String::from_utf8(file.clone()).unwrap(),
+            "export\tdouble\t00000004\nreloc\t00000000\tabs32\n\
+             00000004\n00421020\n10000000\n4fe00000\n"
+        );
+        let lib = read_object(&file[..]).unwrap();
+
+        let linked = link(&[("main.o".to_owned(), main), ("lib.o".to_owned(), lib)]).unwrap();
+        assert_eq!(
+            linked.words,
+            [
+                0x8c02_0010,
+                0x2003_0018,
+                0x4c7f_0000,
+                0xfc00_0000,
+                21,
+                0x18,
+                0x0042_1020,
+                0x1000_0000,
+                0x4fe0_0000
+            ]
+        );
+        assert_eq!(linked.symbols["double"], 0x18);
+
+        let err = link(&[(
+            "main.o".to_owned(),
+            assemble_object("\t.extern\tf\n\tbeqz\t0\t0\tf\n").unwrap(),
+        )]);
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "main.o uses `f`, which no object exports"
+        );
+
+        let diags = crate::assemble("\t.extern\tf\n\taddi\t1\t0\tf\n").unwrap_err();
+        assert_eq!(
+            diags.iter().next().unwrap().message,
+            "`f` is an `.extern`, so it has to be linked"
+        );
+        let diags = assemble_object("x\taddi\t1\t0\tx*2\n").unwrap_err();
+        assert_eq!(
+            diags.iter().next().unwrap().message,
+            "`x*2` can't be relocated"
+        );
+    }
+}
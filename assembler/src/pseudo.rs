@@ -0,0 +1,196 @@
+//! Pseudo-instructions, which the assembler expands into one or more real instructions
+//!
+//! | pseudo           | expansion                                        |
+//! |------------------|--------------------------------------------------|
+//! | `nop`            | `add 0 0 0`                                      |
+//! | `mov rd rs`      | `add rd rs 0`                                    |
+//! | `neg rd rs`      | `sub rd 0 rs`                                    |
+//! | `not rd rs`      | `nor rd rs 0`                                    |
+//! | `b label`        | `beqz 0 0 label`                                 |
+//! | `bnez rs label`  | `bne rs 0 label`                                 |
+//! | `li rt imm`      | `addi rt 0 imm` if `imm` fits in 16 bits         |
+//!
+//! A 32 bit `li` loads the upper half and ors in the lower half: `lui rt hi`, `ori rt rt lo`. Either is left out if
+//! its half is 0, so `li rt 0x8000` is `ori rt 0 0x8000`
+
+use crate::asm::{eval, eval_or_report, Operands, Reloc, Symbols};
+use crate::diag::{Diagnostic, Diagnostics};
+use crate::instr::{Instruction, MathFunc, OpCode};
+use crate::lexer::Token;
+use crate::object::RelocKind;
+
+use std::convert::TryFrom;
+
+use strum_macros::{Display, EnumString};
+
+#[derive(EnumString, Display, Clone, Copy, Debug, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Pseudo {
+    Nop,
+    Mov,
+    Neg,
+    Not,
+    B,
+    Bnez,
+    Li,
+}
+
+impl Pseudo {
+    /// How many words the expansion takes, with only the symbols defined so far
+    pub(crate) fn size(self, operands: &[Token], symbols: &Symbols) -> u32 {
+        match self {
+            Pseudo::Li => match operands
+                .get(1)
+                .and_then(|&imm| eval(imm, symbols).ok())
+                .and_then(|value| li_parts(value.num))
+            {
+                Some((0, _)) | Some((_, 0)) => 1,
+                Some(_) => 2,
+                // Small numbers and later labels are a single addi. Errors are reported when expanding
+                None => 1,
+            },
+            _ => 1,
+        }
+    }
+
+    /// The real instructions for the pseudo-instruction at `pc`
+    pub(crate) fn expand<'a>(
+        self,
+        toks: &mut Operands<'a, '_, impl Iterator<Item = Token<'a>>>,
+        pc: u32,
+        symbols: &Symbols<'a>,
+        diags: &mut Diagnostics,
+    ) -> Option<Vec<Instruction>> {
+        use Instruction as I;
+        let instrs = match self {
+            Pseudo::Nop => vec![I::nop()],
+            Pseudo::Mov | Pseudo::Neg | Pseudo::Not => {
+                let rd = toks.reg(diags);
+                let rs = toks.reg(diags);
+                let (rd, rs) = rd.zip(rs)?;
+                match self {
+                    Pseudo::Mov => vec![I::math(MathFunc::ADD, (rd, rs, 0))],
+                    Pseudo::Neg => vec![I::math(MathFunc::SUB, (rd, 0, rs))],
+                    _ => vec![I::math(MathFunc::NOR, (rd, rs, 0))],
+                }
+            }
+            Pseudo::B => {
+                let target = toks.next(diags, "a label")?;
+                let offset = toks.branch(target, pc, 0, symbols, diags)?;
+                vec![I::i_type(OpCode::BEQ, (0, 0, offset))]
+            }
+            Pseudo::Bnez => {
+                let rs = toks.reg(diags);
+                let offset = toks
+                    .next(diags, "a label")
+                    .and_then(|target| toks.branch(target, pc, 0, symbols, diags));
+                let (rs, offset) = rs.zip(offset)?;
+                vec![I::i_type(OpCode::BNE, (0, rs, offset))]
+            }
+            Pseudo::Li => {
+                let rt = toks.reg(diags);
+                let imm = toks.next(diags, "a 32 bit expression")?;
+                let rt = rt?;
+                let value = eval_or_report(imm, symbols, diags)?;
+                let num = value.num;
+                match li_parts(num) {
+                    Some((hi, lo)) => {
+                        // The halves can't be relocated separately
+                        if value.reloc != Reloc::None {
+                            toks.relocate(0, RelocKind::Abs16, imm, Reloc::Invalid);
+                        }
+                        match (hi, lo) {
+                            (0, lo) => vec![I::i_type(OpCode::ORI, (rt, 0, lo as i16))],
+                            (hi, 0) => vec![I::lui(rt, hi)],
+                            (hi, lo) => {
+                                vec![I::lui(rt, hi), I::i_type(OpCode::ORI, (rt, rt, lo as i16))]
+                            }
+                        }
+                    }
+                    None => match i16::try_from(num) {
+                        Ok(num) => {
+                            toks.relocate(0, RelocKind::Abs16, imm, value.reloc);
+                            vec![I::i_type(OpCode::ADDI, (rt, 0, num))]
+                        }
+                        Err(_) => {
+                            diags.push(
+                                Diagnostic::error(
+                                    imm.span,
+                                    format!("`{}` doesn't fit in 32 bits", imm.text),
+                                )
+                                .with_note(format!(
+                                    "{} is outside of {}..={}",
+                                    num,
+                                    i32::MIN,
+                                    u32::MAX
+                                )),
+                            );
+                            return None;
+                        }
+                    },
+                }
+            }
+        };
+        Some(instrs)
+    }
+}
+
+/// Split a 32 bit `li` constant into its upper and lower halves. `None` if it fits in a single `addi`, or doesn't fit
+/// in 32 bits
+fn li_parts(num: i64) -> Option<(u16, u16)> {
+    if i16::try_from(num).is_ok() || num < i64::from(i32::MIN) || num > i64::from(u32::MAX) {
+        return None;
+    }
+    // Only the low 32 bits matter
+    let num = num as u32;
+    Some(((num >> 16) as u16, num as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble;
+    use crate::instr::Word;
+
+    fn disassemble(source: &str) -> Vec<String> {
+        let program = assemble(source).unwrap();
+        program
+            .words
+            .iter()
+            .map(|&word| Word::from(word).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            disassemble("\tnop\n\tmov\t2\t3\n\tneg\t2\t3\n\tnot\t2\t3\nend\tb\tend\n"),
+            [
+                "add 0 0 0",
+                "add 2 3 0",
+                "sub 2 0 3",
+                "nor 2 3 0",
+                "beqz 0 0 -4"
+            ]
+        );
+        assert_eq!(
+            disassemble("top\tbnez\t4\ttop\n\tli\t5\t-2\n"),
+            ["bne 4 0 -4", "addi 5 0 -2"]
+        );
+        assert_eq!(
+            disassemble("\tli\t2\t40000\n\tli\t3\t-65536\n\tli\t4\t0x12345678\n"),
+            [
+                "ori 2 0 40000",
+                "lui 3 65535",
+                "lui 4 4660",
+                "ori 4 4 22136",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_addresses() {
+        let program = assemble("\tli\t2\t100000\n\tbnez\t2\tend\nend\thalt\n").unwrap();
+        assert_eq!(program.symbols["end"], 12);
+        assert_eq!(program.source_map, [0, 0, 1, 2]);
+    }
+}
@@ -0,0 +1,51 @@
+//! Register names. A register can be written as `5`, `r5`, `$5`, `$r5`, or by its conventional MIPS name like `$t0`
+
+/// The conventional MIPS name of each register, without the `$`
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+    "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp", "fp",
+    "ra",
+];
+
+/// The conventional name of `reg`, with the `$`
+pub fn name(reg: u8) -> String {
+    format!("${}", REGISTER_NAMES[reg as usize])
+}
+
+/// Parse any of the ways to write a register. `None` if it isn't a register, or is out of range
+pub fn parse(text: &str) -> Option<u8> {
+    let (dollar, text) = match text.strip_prefix('$') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let num = text.strip_prefix('r').unwrap_or(text);
+    if num.bytes().all(|b| b.is_ascii_digit()) {
+        return num.parse::<u8>().ok().filter(|&num| num < 32);
+    }
+    // Bare names like `sp` would be mistaken for aliases and labels, so they need the `$`
+    if dollar {
+        REGISTER_NAMES
+            .iter()
+            .position(|&name| name == text)
+            .map(|reg| reg as u8)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(["7", "r7", "$7", "$r7", "$a3"].map(parse), [Some(7); 5]);
+        assert_eq!(parse("$zero"), Some(0));
+        assert_eq!(parse("$ra"), Some(31));
+        assert_eq!(
+            ["32", "$r32", "sp", "$", "r", "rr1", "$x1"].map(parse),
+            [None; 7]
+        );
+        assert_eq!(name(29), "$sp");
+    }
+}
@@ -0,0 +1,681 @@
+//! Scheduling for a pipeline without forwarding or hazard detection, picked with `--schedule`
+//!
+//! Without forwarding, a register can only be read `HAZARD_DISTANCE` instructions after the one that writes it, once
+//! it's been written back. `nops` inserts the fewest `nop`s needed before each instruction. `reorder` first moves
+//! independent instructions within each basic block to fill those gaps, then inserts `nop`s for whatever is left.
+//! HI and LO don't need `nop`s, since `mfhi` and `mflo` wait for the multiply/divide unit themselves, but instructions
+//! using them stay in order.
+//!
+//! Branch offsets, `j`/`jal` targets, labels, and addresses used by `addi`/`lw`/`sw` and `.fill`/`.word` are moved
+//! to match, using the relocations the assembler records. Addresses written as plain numbers aren't, and neither is padding from
+//! `.align` and `.org`. So a program isn't scheduled if a load or store from r0 uses a number that's an address that
+//! moves. A `jalr` could go to one too, so with a `jalr`, neither are numbers in data or `addi` that are the addresses
+//! of instructions that move.
+//!
+//! Hazards across branches are checked against every branch that can reach an instruction. A `jalr` could go anywhere
+//! whose address is used, so everything is written back before a `jalr`, and only its link register is pending where
+//! it might land. The same goes for `syscall` and `break`, whose handler could read any register.
+
+use crate::asm::Program;
+use crate::instr::{Instruction, OpCode, Word};
+use crate::object::RelocKind;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Result};
+use strum_macros::{Display, EnumString};
+
+#[derive(EnumString, Display, Clone, Copy, Debug, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Schedule {
+    /// Only insert `nop`s
+    Nops,
+    /// Reorder instructions within basic blocks, then insert `nop`s
+    Reorder,
+}
+
+/// How many instructions after an instruction its result can be read. It's written back in the first half of a
+/// cycle, and read in the second half
+pub const HAZARD_DISTANCE: u8 = 3;
+
+/// How many more instructions have to be issued before each register can be read
+type Waits = [u8; 32];
+
+/// A run of instructions only entered at the start, and only left at the end
+struct Block {
+    /// Old word indices, in the order they'll be issued
+    order: Vec<usize>,
+    /// Whether execution can continue into the next word after the block
+    falls: bool,
+}
+
+/// Schedule `program`. It has to be assembled as an object, so every address in it is known
+pub fn schedule(program: &Program, mode: Schedule) -> Result<Program> {
+    if let Some(reloc) = program
+        .relocations
+        .iter()
+        .find(|reloc| reloc.symbol.is_some())
+    {
+        bail!(
+            "`{}` is an `.extern`, only whole programs can be scheduled",
+            reloc.symbol.as_deref().unwrap_or_default()
+        );
+    }
+    let len = program.words.len();
+    let line = |i: usize| program.source_map.get(i).map_or(0, |line| line + 1);
+    let instrs: Vec<Option<Instruction>> = program
+        .words
+        .iter()
+        .enumerate()
+        .map(|(i, &bits)| match Word::from(bits) {
+            Word::Instr(instr) if !program.data.contains(&(4 * i as u32)) => Some(instr),
+            _ => None,
+        })
+        .collect();
+
+    // Where branches and jumps go, and every address that's used, which a `jalr` could go to
+    let mut targets = BTreeMap::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        if let Some(target) = instr.and_then(|instr| instr.target(4 * i as u32)) {
+            if target < 0 || target % 4 != 0 || target > 4 * len as i64 {
+                bail!("the branch on line {} goes outside of the program", line(i));
+            }
+            targets.insert(i, (target / 4) as usize);
+        }
+    }
+    let mut taken = BTreeSet::new();
+    for reloc in &program.relocations {
+        taken.insert((reloc_value(program, reloc.addr, reloc.kind) / 4).max(0) as usize);
+    }
+    let mut starts: BTreeSet<usize> = program
+        .symbols
+        .values()
+        .map(|&addr| addr as usize / 4)
+        .collect();
+    starts.insert(0);
+    starts.extend(targets.values());
+    starts.extend(&taken);
+
+    // Split the code into blocks, ending at control instructions and before anything that can be jumped to
+    let mut blocks: BTreeMap<usize, Block> = BTreeMap::new();
+    let mut current: Option<usize> = None;
+    for (i, instr) in instrs.iter().enumerate() {
+        let instr = match instr {
+            Some(instr) => instr,
+            None => {
+                current = None;
+                continue;
+            }
+        };
+        if starts.contains(&i) || current.is_none() {
+            if let Some(start) = current {
+                blocks.get_mut(&start).unwrap().falls = true;
+            }
+            current = Some(i);
+            blocks.insert(
+                i,
+                Block {
+                    order: Vec::new(),
+                    falls: false,
+                },
+            );
+        }
+        let start = current.unwrap();
+        blocks.get_mut(&start).unwrap().order.push(i);
+        if let Some(falls) = control(instr) {
+            blocks.get_mut(&start).unwrap().falls = falls;
+            current = None;
+        }
+    }
+    if mode == Schedule::Reorder {
+        for block in blocks.values_mut() {
+            let block_instrs: Vec<Instruction> =
+                block.order.iter().map(|&i| instrs[i].unwrap()).collect();
+            block.order = reorder(&block_instrs)
+                .into_iter()
+                .map(|j| block.order[j])
+                .collect();
+        }
+    }
+
+    // Find what's pending at the start of each block, until it stops changing
+    let links = instrs.iter().fold([0; 32], |mut waits, instr| {
+        if let Some(Instruction::Jalr { rd, .. }) = instr {
+            if *rd != 0 {
+                waits[*rd as usize] = HAZARD_DISTANCE - 1;
+            }
+        }
+        waits
+    });
+    let mut entries: BTreeMap<usize, Waits> = BTreeMap::new();
+    let mut pads = BTreeMap::new();
+    loop {
+        let mut next: BTreeMap<usize, Waits> =
+            blocks.keys().map(|&start| (start, [0; 32])).collect();
+        for &start in &taken {
+            if let Some(waits) = next.get_mut(&start) {
+                *waits = links;
+            }
+        }
+        for (&start, block) in &blocks {
+            let mut waits = entries.get(&start).copied().unwrap_or([0; 32]);
+            for &i in &block.order {
+                let instr = instrs[i].unwrap();
+                let mut pad = instr
+                    .sources()
+                    .iter()
+                    .flatten()
+                    .map(|&reg| waits[reg as usize])
+                    .max()
+                    .unwrap_or(0);
+                if let Instruction::Jalr { .. } | Instruction::Syscall | Instruction::Break = instr
+                {
+                    // Everything but the link has to be written back wherever it goes, and a handler can read
+                    // anything
+                    pad = pad.max(waits.iter().max().unwrap().saturating_sub(1));
+                }
+                pads.insert(i, pad);
+                for _ in 0..=pad {
+                    waits = waits.map(|wait| wait.saturating_sub(1));
+                }
+                if let Some(dest) = instr.dest().filter(|&dest| dest != 0) {
+                    waits[dest as usize] = HAZARD_DISTANCE - 1;
+                }
+                if let Some(&target) = targets.get(&i) {
+                    if let Some(entry) = next.get_mut(&target) {
+                        *entry = merge(*entry, waits);
+                    }
+                }
+            }
+            let end = block.order.iter().max().unwrap() + 1;
+            if let Some(entry) = next.get_mut(&end).filter(|_| block.falls) {
+                *entry = merge(*entry, waits);
+            }
+        }
+        if next == entries {
+            break;
+        }
+        entries = next;
+    }
+
+    // Lay out the new program, keeping track of where each word went
+    let mut scheduled = Program {
+        exports: program.exports.clone(),
+        ..Program::default()
+    };
+    // Where each old word went, and where execution that reached it now goes
+    let mut moved = vec![0; len];
+    let mut entered = vec![0; len + 1];
+    let mut i = 0;
+    while i < len {
+        let order = match blocks.get(&i) {
+            Some(block) => block.order.clone(),
+            None => vec![i],
+        };
+        entered[i] = scheduled.words.len();
+        for &old in &order {
+            // Only the start of a block can be jumped to, anything else starts at its `nop`s
+            if old != i {
+                entered[old] = scheduled.words.len();
+            }
+            for _ in 0..pads.get(&old).copied().unwrap_or(0) {
+                scheduled.words.push(Instruction::nop().as_u32());
+                scheduled.source_map.push(program.source_map[old]);
+            }
+            moved[old] = scheduled.words.len();
+            if program.data.contains(&(4 * old as u32)) {
+                scheduled.data.insert(4 * moved[old] as u32);
+            }
+            scheduled.words.push(program.words[old]);
+            scheduled.source_map.push(program.source_map[old]);
+        }
+        i += order.len();
+    }
+    entered[len] = scheduled.words.len();
+    let new_addr = |addr: i64| -> Option<i64> {
+        let word = usize::try_from(addr / 4).ok().filter(|&word| word <= len)?;
+        Some(4 * entered[word] as i64 + addr % 4)
+    };
+
+    // Addresses written as plain numbers can't be moved with what they point at. Loads and stores from r0 always use
+    // one, and with a `jalr` so could any number in data or `addi` that's the address of an instruction
+    let jalr = instrs
+        .iter()
+        .any(|instr| matches!(instr, Some(Instruction::Jalr { .. })));
+    let relocated: BTreeSet<usize> = program
+        .relocations
+        .iter()
+        .map(|reloc| reloc.addr as usize / 4)
+        .collect();
+    for (i, instr) in instrs.iter().enumerate() {
+        let (value, code) = match instr {
+            Some(Instruction::Load { rs: 0, imm, .. })
+            | Some(Instruction::Store { rs: 0, imm, .. }) => (i64::from(*imm), false),
+            Some(Instruction::Imm {
+                op: OpCode::ADDI,
+                imm,
+                ..
+            }) if jalr => (i64::from(*imm), true),
+            None if jalr => (i64::from(program.words[i]), true),
+            _ => continue,
+        };
+        let word = value / 4;
+        if relocated.contains(&i)
+            || !(0..4 * len as i64).contains(&value)
+            || code && (value % 4 != 0 || instrs[word as usize].is_none())
+            || new_addr(value) == Some(value)
+        {
+            continue;
+        }
+        bail!(
+            "line {} uses {} as a number, which might be the address of line {}, but that moves to {} once it's \
+             scheduled. Use its label instead",
+            line(i),
+            value,
+            line(word as usize),
+            new_addr(value).unwrap_or(value)
+        );
+    }
+
+    for (&i, &target) in &targets {
+        let word = &mut scheduled.words[moved[i]];
+        if let Some(Instruction::Jump { .. }) = instrs[i] {
+            *word = (*word & 0xfc00_0000) | entered[target] as u32;
+            continue;
+        }
+        let offset = 4 * entered[target] as i64 - 4 * moved[i] as i64 - 4;
+        let imm = i16::try_from(offset).map_err(|_| {
+            anyhow!(
+                "the branch on line {} is too far once it's scheduled",
+                line(i)
+            )
+        })?;
+        *word = (*word & 0xffff_0000) | u32::from(imm as u16);
+    }
+    for reloc in &program.relocations {
+        let old = reloc.addr as usize / 4;
+        let value = new_addr(reloc_value(program, reloc.addr, reloc.kind)).ok_or_else(|| {
+            anyhow!(
+                "the address used on line {} is outside of the program",
+                line(old)
+            )
+        })?;
+        let word = &mut scheduled.words[moved[old]];
+        match reloc.kind {
+            RelocKind::Abs32 => *word = value as u32,
+            RelocKind::Abs26 => *word = (*word & 0xfc00_0000) | (value as u32 >> 2 & 0x03ff_ffff),
+            _ => {
+                let imm = i16::try_from(value).map_err(|_| {
+                    anyhow!(
+                        "the address used on line {} is out of range once it's scheduled",
+                        line(old)
+                    )
+                })?;
+                *word = (*word & 0xffff_0000) | u32::from(imm as u16);
+            }
+        }
+        scheduled.relocations.push(crate::object::Relocation {
+            addr: 4 * moved[old] as u32,
+            ..reloc.clone()
+        });
+    }
+    for (name, &addr) in &program.symbols {
+        let addr = new_addr(i64::from(addr)).unwrap_or(i64::from(addr));
+        scheduled.symbols.insert(name.clone(), addr as u32);
+    }
+    Ok(scheduled)
+}
+
+/// The address in the relocated word at `addr`
+fn reloc_value(program: &Program, addr: u32, kind: RelocKind) -> i64 {
+    let word = program.words[addr as usize / 4];
+    match kind {
+        RelocKind::Abs32 => i64::from(word),
+        RelocKind::Abs26 => i64::from(word & 0x03ff_ffff) << 2,
+        _ => i64::from(word as u16 as i16),
+    }
+}
+
+/// Whether execution continues after a control instruction, or `None` if it isn't one
+fn control(instr: &Instruction) -> Option<bool> {
+    match *instr {
+        Instruction::Halt => Some(false),
+        // A handler can return to the next instruction
+        Instruction::Syscall | Instruction::Break => Some(true),
+        Instruction::Branch { .. } => Some(!instr.always_taken()),
+        Instruction::Jump { link, .. } => Some(link),
+        Instruction::Jalr { rd, .. } => Some(rd != 0),
+        _ => None,
+    }
+}
+
+fn merge(a: Waits, b: Waits) -> Waits {
+    let mut merged = a;
+    for (merged, b) in merged.iter_mut().zip(b) {
+        *merged = (*merged).max(b);
+    }
+    merged
+}
+
+/// An order for a block that needs fewer `nop`s, keeping the control instruction at the end. Instructions move past
+/// each other only if they don't share registers in a way that matters, memory accesses stay in order if either
+/// is a store, and so do instructions that use HI and LO
+fn reorder(instrs: &[Instruction]) -> Vec<usize> {
+    let writes = |instr: &Instruction| instr.dest().filter(|&dest| dest != 0);
+    let reads = |instr: &Instruction, reg: u8| instr.sources().contains(&Some(reg));
+    let is_store = |instr: &Instruction| matches!(instr, Instruction::Store { .. });
+    let is_memory =
+        |instr: &Instruction| is_store(instr) || matches!(instr, Instruction::Load { .. });
+    let depends = |later: usize, earlier: usize| {
+        let (a, b) = (&instrs[earlier], &instrs[later]);
+        control(b).is_some()
+            || writes(a).is_some_and(|reg| reads(b, reg) || writes(b) == Some(reg))
+            || writes(b).is_some_and(|reg| reads(a, reg))
+            || (is_memory(a) && is_memory(b) && (is_store(a) || is_store(b)))
+            || (a.uses_hi_lo() && b.uses_hi_lo())
+    };
+
+    let mut order = Vec::with_capacity(instrs.len());
+    let mut done = vec![false; instrs.len()];
+    let mut waits: Waits = [0; 32];
+    while order.len() < instrs.len() {
+        let ready: Vec<usize> = (0..instrs.len())
+            .filter(|&i| !done[i] && (0..i).all(|j| done[j] || !depends(i, j)))
+            .collect();
+        let stalls = |i: usize| {
+            instrs[i]
+                .sources()
+                .iter()
+                .flatten()
+                .any(|&reg| waits[reg as usize] > 0)
+        };
+        let next = ready
+            .iter()
+            .copied()
+            .find(|&i| !stalls(i))
+            .unwrap_or(ready[0]);
+        let pad = instrs[next]
+            .sources()
+            .iter()
+            .flatten()
+            .map(|&reg| waits[reg as usize])
+            .max()
+            .unwrap_or(0);
+        for _ in 0..=pad {
+            waits = waits.map(|wait| wait.saturating_sub(1));
+        }
+        if let Some(dest) = writes(&instrs[next]) {
+            waits[dest as usize] = HAZARD_DISTANCE - 1;
+        }
+        done[next] = true;
+        order.push(next);
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_object;
+    use crate::instr::{MathFunc, LINK_REGISTER};
+    use crate::mem::{self, Endian};
+
+    /// Run `program` like `small` does, until it halts, traps or takes too long. Gives the registers, HI and LO, then
+    /// its data words in order
+    fn run(program: &Program) -> Vec<u32> {
+        let mut memory = program.words.clone();
+        let (mut regs, mut hi, mut lo) = ([0u32; 32], 0, 0);
+        let mut pc = 0u32;
+        for _ in 0..100_000 {
+            let word = memory.get(pc as usize / 4).copied().unwrap_or(0);
+            pc = pc.wrapping_add(4);
+            let instr = match Word::from(word) {
+                Word::Instr(instr) => instr,
+                Word::Data(..) => break,
+            };
+            match instr {
+                Instruction::Math { func, rs, rt, .. } if func.writes_hi_lo() => {
+                    (hi, lo) = func.eval_hi_lo(regs[rs as usize], regs[rt as usize]);
+                }
+                Instruction::Math {
+                    func: MathFunc::MFHI,
+                    rd,
+                    ..
+                } => regs[rd as usize] = hi,
+                Instruction::Math {
+                    func: MathFunc::MFLO,
+                    rd,
+                    ..
+                } => regs[rd as usize] = lo,
+                Instruction::Math {
+                    func,
+                    rd,
+                    rs,
+                    rt,
+                    shamt,
+                } => {
+                    let (rs, rt) = (regs[rs as usize], regs[rt as usize]);
+                    if func.overflows(rs, rt) {
+                        break;
+                    }
+                    regs[rd as usize] = func.eval(rs, rt, shamt);
+                }
+                Instruction::Load { op, rt, rs, imm } => {
+                    let addr = regs[rs as usize].wrapping_add(imm as i32 as u32);
+                    match mem::load(&memory, addr, op.width(), Endian::Little) {
+                        Ok(value) => regs[rt as usize] = op.extend_load(value),
+                        Err(_) => break,
+                    }
+                }
+                Instruction::Store { op, rt, rs, imm } => {
+                    let addr = regs[rs as usize].wrapping_add(imm as i32 as u32);
+                    let value = regs[rt as usize];
+                    if mem::store(&mut memory, addr, op.width(), value, Endian::Little).is_err() {
+                        break;
+                    }
+                }
+                Instruction::Imm { op, rt, rs, imm } => {
+                    regs[rt as usize] = op.eval_imm(regs[rs as usize], imm)
+                }
+                Instruction::Branch { rs, rt, imm, .. } => {
+                    if instr.taken(regs[rs as usize], regs[rt as usize]) {
+                        pc = pc.wrapping_add(imm as i32 as u32);
+                    }
+                }
+                Instruction::Jump { link, target } => {
+                    if link {
+                        regs[LINK_REGISTER as usize] = pc;
+                    }
+                    pc = pc & !0x0fff_ffff | target << 2;
+                }
+                Instruction::Jalr { rd, rs } => {
+                    let target = regs[rs as usize];
+                    regs[rd as usize] = pc;
+                    pc = target;
+                }
+                Instruction::Mfc0 { rt, .. } => regs[rt as usize] = 0,
+                Instruction::Halt | Instruction::Syscall | Instruction::Break => break,
+            }
+            regs[0] = 0;
+        }
+        let mut values = regs.to_vec();
+        values.extend([hi, lo]);
+        values.extend(program.data.iter().map(|&addr| memory[addr as usize / 4]));
+        values
+    }
+
+    /// What an address points at, since scheduling moves it
+    #[derive(Debug, PartialEq, Eq)]
+    enum Place {
+        /// The nth data word
+        Data(usize),
+        /// An instruction, which could have moved anywhere
+        Code,
+        /// Just past the end of the program
+        End,
+    }
+
+    /// What `value` points at, if it could be an address in `program` other than 0, where both start
+    fn place(program: &Program, value: u32) -> Option<Place> {
+        if value == 0 || value % 4 != 0 || value as usize > 4 * program.words.len() {
+            return None;
+        }
+        Some(match program.data.iter().position(|&addr| addr == value) {
+            Some(n) => Place::Data(n),
+            None if value as usize == 4 * program.words.len() => Place::End,
+            None => Place::Code,
+        })
+    }
+
+    #[test]
+    fn test_schedule() {
+        let source = "\
+\tlw\t1\t0\tnum
+\tlw\t2\t0\tnum
+\tadd\t3\t1\t1
+\taddi\t4\t0\t7
+loop\taddi\t4\t4\t-1
+\tbeqz\t0\t4\tdone
+\tbeqz\t0\t0\tloop
+done\tsw\t3\t0\tnum
+\thalt
+num\t.fill\t5
+";
+        let program = assemble_object(source).unwrap();
+        let nop = "add 0 0 0";
+        let text = |program: &Program| -> Vec<String> {
+            program
+                .words
+                .iter()
+                .map(|&word| Word::from(word).to_string())
+                .collect()
+        };
+
+        let nops = schedule(&program, Schedule::Nops).unwrap();
+        assert_eq!(
+            text(&nops),
+            [
+                "lw 1 0 56",
+                "lw 2 0 56",
+                nop,
+                "add 3 1 1",
+                "addi 4 0 7",
+                nop,
+                nop,
+                "addi 4 4 -1",
+                nop,
+                nop,
+                "beqz 0 4 4",
+                "beqz 0 0 -28",
+                "sw 3 0 56",
+                "halt",
+                "data: 5",
+            ]
+        );
+        assert_eq!(nops.symbols["loop"], 20);
+        assert_eq!(nops.symbols["done"], 48);
+
+        let reordered = schedule(&program, Schedule::Reorder).unwrap();
+        assert_eq!(
+            text(&reordered),
+            [
+                "lw 1 0 48",
+                "lw 2 0 48",
+                "addi 4 0 7",
+                "add 3 1 1",
+                nop,
+                "addi 4 4 -1",
+                nop,
+                nop,
+                "beqz 0 4 4",
+                "beqz 0 0 -24",
+                "sw 3 0 48",
+                "halt",
+                "data: 5",
+            ]
+        );
+
+        // Every word is listed with its line, even the ones that moved
+        let mut listing = Vec::new();
+        reordered.write_listing(source, &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        let (words, _symbols) = listing.split_once("\nSymbols:").unwrap();
+        let listed = |addr: u32| {
+            words
+                .lines()
+                .any(|line| line.starts_with(&format!("{:08x}", addr)))
+        };
+        assert!((0..13).all(|i| listed(4 * i)));
+        assert!(listing.contains("00000008  20040007  addi 4 0 7            \taddi\t4\t0\t7"));
+    }
+
+    #[test]
+    fn test_numeric_addresses() {
+        let source = "\
+\tlw\t1\t0\tptr
+\tadd\t2\t1\t1
+\tjalr\t31\t1
+\thalt
+sub\tjalr\t0\t31
+ptr\t.fill\t@
+";
+        // With a label, `ptr` moves along with `sub`
+        let program = assemble_object(&source.replace('@', "sub")).unwrap();
+        assert!(schedule(&program, Schedule::Nops).is_ok());
+
+        let program = assemble_object(&source.replace('@', "16")).unwrap();
+        let err = schedule(&program, Schedule::Nops).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 6 uses 16 as a number, which might be the address of line 5, but that moves to 28 once it's \
+             scheduled. Use its label instead"
+        );
+
+        // Without a `jalr`, numbers in data are only numbers, but loads and stores from r0 still use addresses
+        let source = "\tlw\t1\t0\t@\n\tadd\t2\t1\t1\n\thalt\nnum\t.fill\t8\n";
+        let program = assemble_object(&source.replace('@', "num")).unwrap();
+        assert!(schedule(&program, Schedule::Nops).is_ok());
+        let program = assemble_object(&source.replace('@', "12")).unwrap();
+        assert!(schedule(&program, Schedule::Nops).is_err());
+    }
+
+    #[test]
+    fn test_public_tests() {
+        // Programs that use addresses written as numbers
+        let rejected = ["publicMixed"];
+        let tests = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests");
+        for entry in std::fs::read_dir(tests).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "mips") {
+                continue;
+            }
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let program = crate::assemble_object_file(&path).unwrap();
+            let before = run(&program);
+            for mode in [Schedule::Nops, Schedule::Reorder] {
+                let scheduled = match schedule(&program, mode) {
+                    Ok(scheduled) => scheduled,
+                    Err(_) if rejected.contains(&name) => continue,
+                    Err(err) => panic!("{} with --schedule={}: {}", name, mode, err),
+                };
+                assert!(!rejected.contains(&name), "{} is scheduled", name);
+                // The same, or addresses of the same thing
+                let after = run(&scheduled);
+                assert!(
+                    before.len() == after.len()
+                        && before.iter().zip(&after).all(|(&old, &new)| {
+                            old == new
+                                || place(&program, old).is_some()
+                                    && place(&program, old) == place(&scheduled, new)
+                        }),
+                    "{} with --schedule={}: {:?} before, {:?} after",
+                    name,
+                    mode,
+                    before,
+                    after
+                );
+            }
+        }
+    }
+}
@@ -1,24 +1,71 @@
-use std::{fs::File, io::BufReader};
+use std::{collections::BTreeMap, convert::TryFrom, fs::File, io::BufReader};
 
 use anyhow::{Context, Result};
+use assembler::{expr, instr::DEFAULT_MUL_DIV_LATENCY, mem::Endian};
 
 use pipe::{state::*, sim::*};
 
 fn main() -> Result<()> {
     use std::env::args;
 
-    let file = args().nth(1).context("Bad cli args")?;
-    let file = File::open(file)?;
-    let mem = BufReader::new(file);
-
-    let state = State::with_memory(mem);
-    run(state)?;
+    // `--names` annotates the registers in the state dumps with their conventional names
+    let (flags, files): (Vec<String>, Vec<String>) =
+        args().skip(1).partition(|arg| arg.starts_with("--"));
+    let names = flags.iter().any(|flag| flag == "--names");
+    // `--mul-div-latency=n` is how many cycles a multiply or divide takes
+    let latency = match flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--mul-div-latency="))
+    {
+        Some(latency) => latency
+            .parse()
+            .ok()
+            .filter(|&latency| latency > 0)
+            .context("--mul-div-latency has to be at least 1 cycle")?,
+        None => DEFAULT_MUL_DIV_LATENCY,
+    };
+    // `--endian=big` puts byte 0 of each word in its high bits
+    let endian = match flags.iter().find_map(|flag| flag.strip_prefix("--endian=")) {
+        Some(endian) => endian
+            .parse()
+            .ok()
+            .context("--endian has to be `little` or `big`")?,
+        None => Endian::default(),
+    };
+    let file = files.into_iter().next().context("Bad cli args")?;
+    // Assembly can be run directly, anything else is a hex file
+    let (mut state, symbols) = if file.ends_with(".mips") {
+        let program = assembler::assemble_path(file.as_ref(), false, endian)?;
+        (State::with_words(program.words), program.symbols)
+    } else {
+        let file = File::open(file)?;
+        (State::with_memory(BufReader::new(file)), BTreeMap::new())
+    };
+    state.mul_div.latency = latency;
+    state.endian = endian;
+    // `--handler=addr` is where exceptions go, instead of trapping. When running assembly it can be a label
+    if let Some(handler) = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--handler="))
+    {
+        let handler = symbols
+            .get(handler)
+            .copied()
+            .or_else(|| expr::parse_int(handler).and_then(|addr| u32::try_from(addr).ok()))
+            .context("--handler has to be an address, or a label in the program")?;
+        state.cp0.handler = Some(handler);
+    }
+    run(state, names)?;
     Ok(())
 }
 
-fn run(mut state: State) -> Result<()> {
+fn run(mut state: State, names: bool) -> Result<()> {
     loop {
-        print!("{}", state);
+        if names {
+            print!("{:#}", state);
+        } else {
+            print!("{}", state);
+        }
         let instructions_count = state.instructions_count + 1;
 
         let f = fetch(state.program_counter, &state.inst_memory);
@@ -26,13 +73,35 @@ fn run(mut state: State) -> Result<()> {
         let (maybe_f, dec_exc) = decode(&state);
         let (program_counter, fet_dec) = maybe_f.unwrap_or(f);
 
-        let (maybe, exc_mem) = execute(&state);
+        let (maybe, exc_mem, mul_div) = execute(&state);
         let (program_counter, fet_dec, dec_exc) =
             maybe.unwrap_or((program_counter, fet_dec, dec_exc));
 
-        let mem_wrt = memory(&state.exc_mem, &mut state.data_memory);
+        // The instruction after one taking an exception is flushed before it can store
+        let mem_wrt = if state.mem_wrt.exception.is_some() {
+            MemoryWrite::default()
+        } else {
+            memory(&state.exc_mem, &mut state.data_memory, state.endian)
+        };
+
+        let (halt, handler, wrt_end) = writeback(&mut state);
 
-        let (halt, wrt_end) = writeback(&mut state);
+        // Flush everything younger than the instruction that took the exception, so it's precise
+        let (program_counter, fet_dec, dec_exc, exc_mem, mem_wrt, mul_div) = match handler {
+            Some(handler) => {
+                let mut mul_div = mul_div;
+                mul_div.cancel();
+                (
+                    handler,
+                    FetchDecode::default(),
+                    DecodeExecute::nop(),
+                    ExecuteMemory::default(),
+                    MemoryWrite::default(),
+                    mul_div,
+                )
+            }
+            None => (program_counter, fet_dec, dec_exc, exc_mem, mem_wrt, mul_div),
+        };
 
         state = State {
             program_counter,
@@ -42,6 +111,7 @@ fn run(mut state: State) -> Result<()> {
             exc_mem,
             mem_wrt,
             wrt_end,
+            mul_div,
             ..state
         };
 
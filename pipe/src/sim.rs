@@ -1,43 +1,51 @@
 use crate::state::*;
+use assembler::cp0::Exception;
 use assembler::instr::*;
+use assembler::mem::{self, Endian};
 
 /// Read the next instruction and perform branch prediction
-pub fn fetch(program_counter: usize, inst_memory: &[Instruction]) -> (usize, FetchDecode) {
-    let instr = *inst_memory
+pub fn fetch(program_counter: usize, inst_memory: &[Word]) -> (usize, FetchDecode) {
+    let instr = inst_memory
         .get(program_counter / 4)
-        // If we are reading out of bound (past a halt) then read a nop
-        // C version does Instruction::from(0), but when later stages use this it may panic
-        .unwrap_or(&Instruction::from(0));
+        .copied()
+        // If we are reading out of bound (past a halt) then read a 0, like the C version.
+        // It's data, so it's an illegal instruction if it's executed
+        .unwrap_or_else(|| Word::from(0));
 
     let mut pc = program_counter + 4;
 
-    let pc_next = if let OpCode::BEQZ = instr.opcode() {
-        let branch = instr.as_i().unwrap();
-        let offset = branch.imm() as i16;
-        if offset.is_positive() {
-            // predict branch not taken if jumping forward
-            pc
-        } else {
-            // predict branch taken
-            // TODO Check conversion
-            let offset = isize::from(offset) as usize;
+    let pc_next = match instr {
+        Word::Instr(Instruction::Branch { imm: offset, .. }) => {
+            if offset.is_positive() {
+                // predict branch not taken if jumping forward
+                pc
+            } else {
+                // predict branch taken
+                // TODO Check conversion
+                let offset = isize::from(offset) as usize;
+                let old_pc = pc;
+                pc = pc.wrapping_add(offset);
+                old_pc
+            }
+        }
+        // The target is in the instruction, so a jump is never mispredicted
+        Word::Instr(instr @ Instruction::Jump { .. }) => {
             let old_pc = pc;
-            pc = pc.wrapping_add(offset);
+            pc = instr.target(program_counter as u32).unwrap() as usize;
             old_pc
         }
-    } else {
-        pc
+        _ => pc,
     };
     (pc, FetchDecode { instr, pc_next })
 }
 
-/// Read registers, possible stalling if we need to wait for a load
+/// Read registers, possible stalling if we need to wait for a load or the multiply/divide unit
 pub fn decode(state: &State) -> (Option<(usize, FetchDecode)>, DecodeExecute) {
     let instr = state.fet_dec.instr;
     let old_instr = state.dec_exc.instr;
 
-    // SAFETY: ITypeInstructions are invalid iff. their opcode is invalid. But Instructions are guaranteed to have valid opcodes. Not using checked version because we're disregarding the opcode
-    let i_instr = unsafe { &instr.i };
+    // The register file is read before we know what kind of instruction this is, so read whatever is in rs and rt's place
+    let i_instr = &i_fields(instr);
 
     // By default, forward the output of ifid
     let default = (
@@ -64,29 +72,43 @@ pub fn decode(state: &State) -> (Option<(usize, FetchDecode)>, DecodeExecute) {
         DecodeExecute::nop(),
     );
 
-    if matches!(old_instr.opcode(), OpCode::LW) && {
-        // If we the previous instruction was a load
-        let load = old_instr.as_i().unwrap();
-        let dst = load.rt(); // rt or rs?
-                             // And it was into a register we're reading from
-        (matches!(instr.opcode(), OpCode::MATH) && (dst == i_instr.rs() || dst == i_instr.rt()))
-            || (!matches!(instr.opcode(), OpCode::HALT) && i_instr.rs() == dst)
-    } {
-        // Then we have to stall
-        alt
-    } else {
-        default
+    // Whether the multiply/divide unit will still be working next cycle, when this instruction is executed. An
+    // operation whose instruction is in execute or memory hasn't been written back, so HI and LO aren't written yet
+    let mul_div = |word: Word| matches!(word, Word::Instr(Instruction::Math { func, .. }) if func.writes_hi_lo());
+    let unit_busy =
+        mul_div(old_instr) || mul_div(state.exc_mem.instr) || state.mul_div.remaining > 1;
+
+    match old_instr {
+        // If we the previous instruction was a load into a register we're reading from, then we have to stall. Like
+        // the reference simulator, only math (and data, which looks like it) stalls on rt, everything else on rs
+        Word::Instr(Instruction::Load { rt: dst, .. })
+            if match instr {
+                Word::Instr(Instruction::Math { .. }) | Word::Data(..) => {
+                    sources(instr).contains(&Some(dst))
+                }
+                _ => sources(instr)[0] == Some(dst),
+            } =>
+        {
+            alt
+        }
+        // `mfhi` and `mflo` wait for the result, and a multiply or divide waits for the unit to be free
+        _ if unit_busy && matches!(instr, Word::Instr(instr) if instr.uses_hi_lo()) => alt,
+        _ => default,
     }
 }
 
-pub fn execute(state: &State) -> (Option<(usize, FetchDecode, DecodeExecute)>, ExecuteMemory) {
+/// Also gives the multiply/divide unit after this cycle
+pub fn execute(
+    state: &State,
+) -> (
+    Option<(usize, FetchDecode, DecodeExecute)>,
+    ExecuteMemory,
+    MulDiv,
+) {
     let (instr, read_reg_a, read_reg_b) = {
         let instr = state.dec_exc.instr;
 
-        // SAFETY: All instructions are valid in the i format
-        let i = unsafe { instr.i };
-        let r1 = i.rs();
-        let r2 = i.rt();
+        let [r1, r2] = sources(instr);
 
         let (ex_a, ex_b) = forward_to_exc(state.exc_mem.instr, state.exc_mem.alu_result, r1, r2);
 
@@ -102,10 +124,8 @@ pub fn execute(state: &State) -> (Option<(usize, FetchDecode, DecodeExecute)>, E
         let (wrt_a, wrt_b) = forward_to_exc(state.wrt_end.instr, state.wrt_end.write_data, r1, r2);
 
         // The forward function doesn't handle forwarding from store
-        let wrt_b = if matches!(state.wrt_end.instr.opcode(), OpCode::SW) {
-            let old_wb = state.wrt_end.instr.as_i().unwrap();
-
-            if r2 != 0 && r2 == old_wb.rt() {
+        let wrt_b = if let Word::Instr(Instruction::Store { rt, .. }) = state.wrt_end.instr {
+            if rt != 0 && r2 == Some(rt) {
                 Some(state.wrt_end.write_data)
                 // TODO? Some(math);
             } else {
@@ -122,58 +142,76 @@ pub fn execute(state: &State) -> (Option<(usize, FetchDecode, DecodeExecute)>, E
     };
 
     let mut extra = None;
+    let mut exception = None;
+    let mut mul_div = state.mul_div;
+    if let Word::Instr(Instruction::Math { func, .. }) = state.mem_wrt.instr {
+        if func.writes_hi_lo() {
+            mul_div.retire();
+        }
+    }
 
-    let (alu_result, read_reg) = match instr.opcode() {
-        OpCode::MATH => {
-            let instr = *{
-                // TODO how to handle data?
-                if instr.as_data().is_ok() {
-                    Instruction::nop()
-                } else {
-                    instr
-                }
-            }
-            .as_r()
-            .unwrap();
+    let instr_word = instr;
+    let instr = match instr {
+        Word::Instr(instr) => instr,
+        // Data does nothing, but it's an exception once it's written back
+        Word::Data(bits, err) => {
+            exception = Some(Exception::Illegal(bits, err));
+            Instruction::nop()
+        }
+    };
 
-            let (alu, read) = if instr == RTypeInstruction::nop() {
+    let (alu_result, read_reg) = match instr {
+        // Decode made sure the unit is free
+        Instruction::Math { func, .. } if func.writes_hi_lo() => {
+            mul_div.start(func.eval_hi_lo(read_reg_a, read_reg_b));
+            (0, read_reg_b)
+        }
+        Instruction::Math {
+            func: MathFunc::MFHI,
+            ..
+        } => (state.mul_div.hi, 0),
+        Instruction::Math {
+            func: MathFunc::MFLO,
+            ..
+        } => (state.mul_div.lo, 0),
+        Instruction::Math { func, shamt, .. } => {
+            if instr == Instruction::nop() {
                 (0, 0)
             } else {
-                let fun = instr.func().as_fn();
-                (fun(read_reg_a, read_reg_b), read_reg_b)
-            };
-            (alu, read)
-        }
-        OpCode::LW => {
-            let i = instr.as_i().unwrap();
-            (
-                u32::wrapping_add(read_reg_a, i.imm() as u32), // TODO: imm or imm_i32?
-                state.registers[i.rt() as usize],
-            )
+                if func.overflows(read_reg_a, read_reg_b) {
+                    exception = Some(Exception::Overflow);
+                }
+                (func.eval(read_reg_a, read_reg_b, shamt), read_reg_b)
+            }
         }
-        OpCode::SW => {
-            let i = instr.as_i().unwrap();
-            (u32::wrapping_add(read_reg_a, i.imm() as u32), read_reg_b)
+        Instruction::Load { rt, imm, .. } => (
+            u32::wrapping_add(read_reg_a, sign_extend(imm)),
+            state.registers[rt as usize],
+        ),
+        Instruction::Store { imm, .. } => {
+            (u32::wrapping_add(read_reg_a, sign_extend(imm)), read_reg_b)
         }
-        OpCode::ADDI => {
-            let i = instr.as_i().unwrap();
-            (
-                u32::wrapping_add(read_reg_a, i.imm_as_i32() as u32),
-                state.dec_exc.read_reg_b,
-            )
+        Instruction::Imm { op, imm, .. } => {
+            (op.eval_imm(read_reg_a, imm), state.dec_exc.read_reg_b)
         }
-        OpCode::BEQZ => {
+        Instruction::Branch { rt, imm, .. } => {
             use std::convert::TryInto;
 
             let offs = state.dec_exc.offset();
-            if (offs > 0 && read_reg_a == 0) || (offs < 0 && read_reg_a != 0) {
+            // Fetch predicted backward branches taken and forward branches not taken
+            let taken = instr.taken(read_reg_a, read_reg_b);
+            if offs != 0 && taken != (offs < 0) {
                 /* Incorrect branch prediction */
-                let program_counter = state.dec_exc.pc_next.wrapping_add(offs as usize);
+                let program_counter = if taken {
+                    state.dec_exc.pc_next.wrapping_add(offs as usize)
+                } else {
+                    state.dec_exc.pc_next
+                };
 
                 /* Wipe out the previous stages in the pipeline */
 
                 let fet = FetchDecode {
-                    instr: Instruction::nop(),
+                    instr: Instruction::nop().into(),
                     pc_next: 0,
                 };
 
@@ -181,115 +219,170 @@ pub fn execute(state: &State) -> (Option<(usize, FetchDecode, DecodeExecute)>, E
                 extra = Some((program_counter, fet, dec))
             }
 
-            let i = instr.as_i().unwrap();
             (
-                u32::wrapping_add(
-                    state.dec_exc.pc_next.try_into().unwrap(),
-                    i.imm_as_i32() as u32,
-                ),
-                state.registers[i.rt() as usize],
+                u32::wrapping_add(state.dec_exc.pc_next.try_into().unwrap(), sign_extend(imm)),
+                state.registers[rt as usize],
+            )
+        }
+        Instruction::Halt => (0, 0),
+        Instruction::Syscall => {
+            exception = Some(Exception::Syscall);
+            (0, 0)
+        }
+        Instruction::Break => {
+            exception = Some(Exception::Break);
+            (0, 0)
+        }
+        Instruction::Mfc0 { rd, .. } => (state.cp0.read(rd), 0),
+        // Fetch already jumped, `jal`'s return address is written back to the link register
+        Instruction::Jump { .. } => (state.dec_exc.pc_next as u32, 0),
+        Instruction::Jalr { rd, .. } => {
+            // Fetch can't predict a register target, so always jump to it and flush the younger instructions
+            let fet = FetchDecode {
+                instr: Instruction::nop().into(),
+                pc_next: 0,
+            };
+            extra = Some((read_reg_a as usize, fet, DecodeExecute::nop()));
+
+            (
+                // The return address is written back to rd
+                state.dec_exc.pc_next as u32,
+                state.registers[rd as usize],
             )
         }
-        OpCode::HALT => (0, 0),
-        OpCode::JALR => unimplemented!(),
     };
 
+    mul_div.tick();
+
     (
         extra,
         ExecuteMemory {
-            instr,
+            instr: instr_word,
+            pc_next: state.dec_exc.pc_next,
             alu_result,
             read_reg,
+            exception,
         },
+        mul_div,
     )
 }
 
+/// Forward `old_save` to whichever of the registers `r1` and `r2` `old_instr` writes to
 fn forward_to_exc(
-    old_instr: Instruction,
+    old_instr: Word,
     old_save: u32,
-    r1: u8,
-    r2: u8,
+    r1: Option<u8>,
+    r2: Option<u8>,
 ) -> (Option<u32>, Option<u32>) {
-    match old_instr.opcode() {
-        OpCode::MATH => {
-            let old_instr = old_instr.as_r().unwrap();
-
-            (
-                if r1 != 0 && r1 == old_instr.rd() {
-                    Some(old_save)
-                } else {
-                    None
-                },
-                if r2 != 0 && r2 == old_instr.rd() {
-                    Some(old_save)
-                } else {
-                    None
-                },
-            )
-        }
-        OpCode::LW | OpCode::BEQZ | OpCode::ADDI => {
-            let old_instr = old_instr.as_i().unwrap();
-            (
-                if r1 != 0 && r1 == old_instr.rt() {
-                    Some(old_save)
-                } else {
-                    None
-                },
-                if r2 != 0 && r2 == old_instr.rt() {
-                    Some(old_save)
-                } else {
-                    None
-                },
-            )
-        }
-        OpCode::SW | OpCode::JALR | OpCode::HALT => (None, None),
-    }
+    let dest = match old_instr {
+        Word::Instr(instr) => instr.dest(),
+        Word::Data(..) => None,
+    };
+    let forward = |reg: Option<u8>| match (reg, dest) {
+        (Some(reg), Some(dest)) if reg != 0 && reg == dest => Some(old_save),
+        _ => None,
+    };
+    (forward(r1), forward(r2))
 }
 
 /// Store and (forwarding for Load) is performed in this stage
-pub fn memory(exc_mem: &ExecuteMemory, data_memory: &mut [u32]) -> MemoryWrite {
+pub fn memory(exc_mem: &ExecuteMemory, data_memory: &mut [u32], endian: Endian) -> MemoryWrite {
     let instr = exc_mem.instr;
-
-    let write_data = match instr.opcode() {
-        OpCode::LW => data_memory[(exc_mem.alu_result / 4) as usize],
-        OpCode::SW => {
-            let store = instr.as_i().unwrap();
-            let addr = store.imm_as_i32() as u32;
-            let offset = store.rs().into();
+    let addr = exc_mem.alu_result;
+
+    let access = match instr {
+        // An instruction with an exception doesn't get to access memory
+        _ if exc_mem.exception.is_some() => Ok(exc_mem.alu_result),
+        Word::Instr(Instruction::Load { op, .. }) => {
+            mem::load(data_memory, addr, op.width(), endian)
+                .map(|value| op.extend_load(value))
+                .map_err(Exception::AddressLoad)
+        }
+        Word::Instr(Instruction::Store { op, .. }) => {
             let val_to_store = exc_mem.read_reg;
-            data_memory[(addr.wrapping_add(offset) / 4) as usize] = val_to_store;
-            val_to_store
+            mem::store(data_memory, addr, op.width(), val_to_store, endian)
+                .map(|_| val_to_store)
+                .map_err(Exception::AddressStore)
         }
-        OpCode::ADDI | OpCode::BEQZ | OpCode::HALT | OpCode::MATH => exc_mem.alu_result,
-        OpCode::JALR => unimplemented!(),
+        _ => Ok(exc_mem.alu_result),
     };
 
-    MemoryWrite { instr, write_data }
+    MemoryWrite {
+        instr,
+        pc_next: exc_mem.pc_next,
+        write_data: access.unwrap_or(0),
+        exception: exc_mem.exception.or(access.err()),
+    }
 }
 
-/// Write back to registers
-pub fn writeback(state: &mut State) -> (bool, WriteEnd) {
-    let instr = state.mem_wrt.instr;
+/// Write back to registers, or take the instruction's exception instead. Also gives the handler to flush the
+/// pipeline and continue at
+pub fn writeback(state: &mut State) -> (bool, Option<usize>, WriteEnd) {
     let wbe = state.mem_wrt;
+    if let Some(exception) = wbe.exception {
+        let pc = wbe.pc_next.wrapping_sub(4);
+        return match state.cp0.take(exception, pc as u32) {
+            Some(handler) => {
+                println!(
+                    "{} exception at pc {}, handled at {}",
+                    exception, pc, handler
+                );
+                // Nothing was written, so there's nothing to forward
+                (false, Some(handler as usize), WriteEnd::default())
+            }
+            None => {
+                println!("{} trap: {}", exception, exception.details(wbe.instr));
+                halted(state);
+                (true, None, wbe)
+            }
+        };
+    }
+    // Data always has an exception
+    let instr = match wbe.instr {
+        Word::Instr(instr) => instr,
+        Word::Data(..) => return (false, None, wbe),
+    };
 
-    match instr.opcode() {
-        OpCode::LW | OpCode::ADDI => {
-            let instr = instr.as_i().unwrap();
-            state.registers[instr.rt() as usize] = wbe.write_data;
-        }
+    match instr.dest() {
+        // r0 must always be 0
+        Some(0) | None => {}
+        Some(dest) => state.registers[dest as usize] = wbe.write_data,
+    }
 
-        OpCode::SW | OpCode::BEQZ => { /* nop */ }
+    if let Instruction::Halt = instr {
+        halted(state);
+    }
 
-        OpCode::MATH => {
-            let instr = instr.as_r().unwrap();
-            state.registers[instr.rd() as usize] = wbe.write_data;
-        }
-        OpCode::HALT => {
-            println!("machine halted");
-            println!("total of {} cycles executed", state.instructions_count);
+    (matches!(instr, Instruction::Halt), None, wbe)
+}
+
+fn halted(state: &State) {
+    println!("machine halted");
+    println!("total of {} cycles executed", state.instructions_count);
+    if state.mul_div.busy_cycles > 0 {
+        println!(
+            "multiply/divide unit busy for {} cycles",
+            state.mul_div.busy_cycles
+        );
+    }
+}
+
+/// The registers read into the alu. Data is read like a math instruction, since that's what it looks like to the hazard logic
+fn sources(word: Word) -> [Option<u8>; 2] {
+    match word {
+        Word::Instr(instr) => instr.sources(),
+        Word::Data(..) => {
+            let i = i_fields(word);
+            [Some(i.rs()), Some(i.rt())]
         }
-        OpCode::JALR => unimplemented!(),
     }
+}
+
+/// View any word in the i type format, to read the register and immediate fields even if it's a different type of instruction or data
+fn i_fields(word: Word) -> ITypeInstruction {
+    ITypeInstruction::from_bytes(word.bits().to_le_bytes())
+}
 
-    (matches!(instr.opcode(), OpCode::HALT), wbe)
+fn sign_extend(imm: i16) -> u32 {
+    i32::from(imm) as u32
 }
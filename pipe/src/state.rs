@@ -1,14 +1,22 @@
-use assembler::instr::Instruction;
+use assembler::{
+    cp0::{Cp0, Exception},
+    instr::{Instruction, Word, DEFAULT_MUL_DIV_LATENCY},
+    mem::Endian,
+    reg,
+};
 use std::{fmt::Display, io::BufRead};
 
 #[derive(Clone, Default)]
 pub struct State {
-    pub inst_memory: Vec<Instruction>,
-    /// Memory *should* be byte addressable, but in the tests loads and stores are always word aligned
+    pub inst_memory: Vec<Word>,
+    /// Loads and stores address its bytes, see [`assembler::mem`]
     pub data_memory: Vec<u32>,
+    pub endian: Endian,
     pub registers: [u32; 32],
     pub program_counter: usize,
     pub instructions_count: usize,
+    pub mul_div: MulDiv,
+    pub cp0: Cp0,
 
     // Forwarding contents
     pub fet_dec: FetchDecode,
@@ -19,24 +27,27 @@ pub struct State {
 }
 
 impl State {
+    /// Read memory from a hex file, one word per line
     pub fn with_memory(memory: impl BufRead) -> Self {
-        let data_memory: Vec<u32> = memory
+        let data_memory = memory
             .lines()
-            .enumerate()
-            .map(|(idx, line)| {
+            .map(|line| {
                 let line = line.unwrap();
-                let bits: u32 = u32::from_str_radix(&line, 16).unwrap();
-                println!("memory[{}]={:x}", idx, bits);
-                bits
+                u32::from_str_radix(&line, 16).unwrap()
             })
             .collect();
+        Self::with_words(data_memory)
+    }
+
+    pub fn with_words(data_memory: Vec<u32>) -> Self {
+        for (idx, bits) in data_memory.iter().enumerate() {
+            println!("memory[{}]={:x}", idx, bits);
+        }
 
         println!("{} memory words", data_memory.len());
 
-        let inst_memory: Vec<Instruction> = data_memory
-            .iter()
-            .map(|word| Instruction::from(*word))
-            .collect();
+        // Data words are kept too, they only trap if they are executed
+        let inst_memory: Vec<Word> = data_memory.iter().map(|&word| Word::from(word)).collect();
 
         println!("\tinstruction memory:");
         for (key, &val) in inst_memory.iter().enumerate() {
@@ -67,7 +78,29 @@ impl Display for State {
 
         writeln!(f, "\tregisters:")?;
         for (key, &val) in self.registers.iter().enumerate() {
-            writeln!(f, "\t\treg[ {} ] {}", key, val as i32)?;
+            // `{:#}` annotates registers with their conventional names
+            if f.alternate() {
+                writeln!(
+                    f,
+                    "\t\treg[ {} ] {} {}",
+                    key,
+                    reg::name(key as u8),
+                    val as i32
+                )?;
+            } else {
+                writeln!(f, "\t\treg[ {} ] {}", key, val as i32)?;
+            }
+        }
+        // Only once it's been used, so programs without multiplies look like they always have
+        if self.mul_div.busy_cycles > 0 {
+            writeln!(f, "\thi {}", self.mul_div.hi as i32)?;
+            writeln!(f, "\tlo {}", self.mul_div.lo as i32)?;
+        }
+        // Only once an exception has been taken
+        if self.cp0.taken > 0 {
+            writeln!(f, "\tepc {}", self.cp0.epc)?;
+            writeln!(f, "\tcause {}", self.cp0.cause)?;
+            writeln!(f, "\tbadVAddr {}", self.cp0.bad_vaddr)?;
         }
 
         writeln!(f, "\tIFID:")?;
@@ -95,13 +128,13 @@ impl Display for State {
 
 #[derive(Default, Clone, Copy)]
 pub struct FetchDecode {
-    pub instr: Instruction,
+    pub instr: Word,
     pub pc_next: usize,
 }
 
 #[derive(Default, Clone, Copy)]
 pub struct DecodeExecute {
-    pub instr: Instruction,
+    pub instr: Word,
     pub pc_next: usize,
     pub read_reg_a: u32,
     pub read_reg_b: u32,
@@ -111,8 +144,7 @@ pub struct DecodeExecute {
 impl DecodeExecute {
     pub fn nop() -> Self {
         Self {
-            // SAFETY: ITypeInstructions are invalid iff. their opcode is invalid. But Instructions are guaranteed to have valid opcodes. Not using checked version because we're disregarding the opcode
-            offset: unsafe { Instruction::nop().i }.imm() as i16, // offset = MathFunc::Add = 32,
+            offset: Instruction::nop().as_u32() as i16, // offset = MathFunc::Add = 32,
             ..Default::default()
         }
     }
@@ -124,19 +156,117 @@ impl DecodeExecute {
 
 #[derive(Default, Clone, Copy)]
 pub struct ExecuteMemory {
-    pub instr: Instruction,
+    pub instr: Word,
+    pub pc_next: usize,
     pub alu_result: u32,
     pub read_reg: u32,
+    /// What the instruction caused in execute. It doesn't access memory, and the exception is taken in writeback
+    pub exception: Option<Exception>,
 }
 
 #[derive(Default, Clone, Copy)]
 pub struct MemoryWrite {
-    pub instr: Instruction,
+    pub instr: Word,
+    /// Where the instruction's exception returns to, plus 4
+    pub pc_next: usize,
     pub write_data: u32,
+    /// What the instruction caused in execute or memory. It's taken in writeback, instead of writing the result
+    pub exception: Option<Exception>,
 }
 
 pub type WriteEnd = MemoryWrite;
 
+/// The multiply/divide unit. It isn't pipelined, so it works on one `mult`, `multu`, `div` or `divu` at a time, and
+/// writes HI and LO once it's done and the instruction has been written back. Until then an exception can cancel it
+#[derive(Clone, Copy)]
+pub struct MulDiv {
+    pub hi: u32,
+    pub lo: u32,
+    /// Cycles each operation takes, at least 1
+    pub latency: usize,
+    /// Cycles until the current operation is done, 0 if the unit is idle
+    pub remaining: usize,
+    /// The `(HI, LO)` of the operation that hasn't been written yet
+    pub result: Option<(u32, u32)>,
+    /// Whether the current operation's instruction has been written back
+    pub retired: bool,
+    /// Cycles spent working so far
+    pub busy_cycles: usize,
+}
+
+impl Default for MulDiv {
+    fn default() -> Self {
+        Self {
+            hi: 0,
+            lo: 0,
+            latency: DEFAULT_MUL_DIV_LATENCY,
+            remaining: 0,
+            result: None,
+            retired: false,
+            busy_cycles: 0,
+        }
+    }
+}
+
+impl MulDiv {
+    /// Start working on an operation with the result `(hi, lo)`. It has to be idle by the end of this cycle
+    pub fn start(&mut self, result: (u32, u32)) {
+        self.result = Some(result);
+        self.remaining = self.latency;
+        self.retired = false;
+    }
+
+    /// The current operation's instruction is being written back
+    pub fn retire(&mut self) {
+        self.retired = true;
+    }
+
+    /// Drop the current operation if its instruction is being flushed by an exception
+    pub fn cancel(&mut self) {
+        if !self.retired {
+            self.result = None;
+            self.remaining = 0;
+        }
+    }
+
+    /// Finish a cycle, writing HI and LO if the operation is done and retired
+    pub fn tick(&mut self) {
+        if self.remaining > 0 {
+            self.busy_cycles += 1;
+            self.remaining -= 1;
+        }
+        if self.remaining == 0 && self.retired {
+            if let Some((hi, lo)) = self.result.take() {
+                (self.hi, self.lo) = (hi, lo);
+            }
+        }
+    }
+}
+
+#[test]
+fn mul_div_latency() {
+    let mut mul_div = MulDiv {
+        latency: 2,
+        ..Default::default()
+    };
+    mul_div.start((1, 2));
+    mul_div.tick();
+    assert_eq!((mul_div.hi, mul_div.lo, mul_div.remaining), (0, 0, 1));
+    mul_div.tick();
+    // Done, but not written back yet
+    assert_eq!((mul_div.hi, mul_div.lo, mul_div.remaining), (0, 0, 0));
+    mul_div.retire();
+    mul_div.tick();
+    assert_eq!((mul_div.hi, mul_div.lo), (1, 2));
+    assert_eq!(mul_div.busy_cycles, 2);
+
+    // Flushed before it was written back
+    mul_div.start((3, 4));
+    mul_div.cancel();
+    mul_div.tick();
+    assert_eq!((mul_div.hi, mul_div.lo, mul_div.remaining), (1, 2, 0));
+}
+
 #[test]
 fn data_print() {
     use std::io::Cursor;
@@ -1,94 +1,214 @@
-use std::{fs::File, io::BufReader};
+use std::{collections::BTreeMap, convert::TryFrom, fs::File, io::BufReader};
 
 use anyhow::{Context, Result};
+use assembler::cp0::Exception;
+use assembler::expr;
 use assembler::instr::*;
+use assembler::mem::{self, Endian};
 use small::state::State;
 
 fn main() -> Result<()> {
     use std::env::args;
 
-    let file = args().nth(1).context("Bad cli args")?;
-    let file = File::open(file)?;
-    let mem = BufReader::new(file);
-    let mut state: State = State::with_memory(mem);
+    // `--names` annotates the registers in the state dumps with their conventional names
+    let (flags, files): (Vec<String>, Vec<String>) =
+        args().skip(1).partition(|arg| arg.starts_with("--"));
+    let names = flags.iter().any(|flag| flag == "--names");
+    // `--mul-div-latency=n` is how many cycles a multiply or divide keeps its unit busy
+    let latency = match flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--mul-div-latency="))
+    {
+        Some(latency) => latency
+            .parse()
+            .ok()
+            .filter(|&latency| latency > 0)
+            .context("--mul-div-latency has to be at least 1 cycle")?,
+        None => DEFAULT_MUL_DIV_LATENCY,
+    };
+    // `--endian=big` puts byte 0 of each word in its high bits
+    let endian = match flags.iter().find_map(|flag| flag.strip_prefix("--endian=")) {
+        Some(endian) => endian
+            .parse()
+            .ok()
+            .context("--endian has to be `little` or `big`")?,
+        None => Endian::default(),
+    };
+    let file = files.into_iter().next().context("Bad cli args")?;
+    // Assembly can be run directly, anything else is a hex file
+    let (mut state, symbols): (State, _) = if file.ends_with(".mips") {
+        let program = assembler::assemble_path(file.as_ref(), false, endian)?;
+        (State::with_words(program.words), program.symbols)
+    } else {
+        let file = File::open(file)?;
+        (State::with_memory(BufReader::new(file)), BTreeMap::new())
+    };
+    state.endian = endian;
+    // `--handler=addr` is where exceptions go, instead of trapping. When running assembly it can be a label
+    if let Some(handler) = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--handler="))
+    {
+        let handler = symbols
+            .get(handler)
+            .copied()
+            .or_else(|| expr::parse_int(handler).and_then(|addr| u32::try_from(addr).ok()))
+            .context("--handler has to be an address, or a label in the program")?;
+        state.cp0.handler = Some(handler);
+    }
 
-    run(&mut state)?;
+    run(&mut state, names, latency)?;
 
     Ok(())
 }
 
-fn run(state: &mut State) -> Result<()> {
+fn run(state: &mut State, names: bool, latency: usize) -> Result<()> {
+    let dump = |state: &State| {
+        if names {
+            println!("{:#}", state)
+        } else {
+            println!("{}", state)
+        }
+    };
+
     loop {
-        use std::ops::{BitAnd, BitOr, Shl, Shr};
-        let instr: Instruction = state.memory[state.program_counter / 4].into();
+        let pc = state.program_counter;
+        // Past the end of memory is all zero, which is data, like pipe fetches it
+        let word = state.memory.get(pc / 4).copied().unwrap_or(0);
         state.program_counter += 4;
 
-        match instr.opcode() {
-            OpCode::MATH => {
-                let instr = instr.as_r()?;
-                let fun: fn(u32, u32) -> u32 = match instr.func() {
-                    MathFunc::ADD => u32::wrapping_add,
-                    MathFunc::SUB => u32::wrapping_sub,
-                    MathFunc::SLL => Shl::shl,
-                    MathFunc::SRL => Shr::shr,
-                    MathFunc::AND => BitAnd::bitand,
-                    MathFunc::OR => BitOr::bitor,
-                };
-                state.registers[instr.rd() as usize] = fun(
-                    state.registers[instr.rs() as usize],
-                    state.registers[instr.rt() as usize],
-                )
-            }
-
-            OpCode::LW | OpCode::SW | OpCode::ADDI | OpCode::BEQZ => {
-                let instr = instr.as_i()?;
-                // TODO Note the mapping between rt/rs and 0/1
-                let reg0 = instr.rs() as usize;
-                let reg1 = instr.rt() as usize;
-                let imm = sign_extend(instr.imm());
-                match instr.opcode() {
-                    OpCode::LW => {
-                        state.registers[reg1] =
-                            state.memory[(state.registers[reg0] + imm) as usize >> 2]
-                    }
-                    OpCode::SW => {
-                        state.memory[(state.registers[reg0] + imm) as usize >> 2] =
-                            state.registers[reg1]
-                    }
-                    OpCode::ADDI => state.registers[reg1] = state.registers[reg0] + imm,
-                    OpCode::BEQZ => {
-                        if state.registers[reg0] == 0 {
-                            state.program_counter =
-                                (state.program_counter as u32).wrapping_add(imm) as usize;
-                        }
-                    }
-                    _ => unreachable!(),
-                }
-            }
-            OpCode::JALR => unimplemented!(),
-            OpCode::HALT => {
+        match step(state, word, latency) {
+            Ok(true) => {
                 println!("machine halted");
                 println!(
                     "total of {} instructions executed",
                     state.num_executed_instructions + 1 // halt counts as an instruction but doesn't add to the count
                 );
-                println!("{}", state);
+                if state.mul_div_busy > 0 {
+                    println!(
+                        "multiply/divide unit busy for {} cycles",
+                        state.mul_div_busy
+                    );
+                }
+                dump(state);
                 break;
             }
+            Ok(false) => {}
+            // The instruction didn't change anything, so the state is as it was before it
+            Err(exception) => match state.cp0.take(exception, pc as u32) {
+                Some(handler) => {
+                    println!(
+                        "{} exception at pc {}, handled at {}",
+                        exception, pc, handler
+                    );
+                    state.program_counter = handler as usize;
+                }
+                None => {
+                    println!(
+                        "{} trap at pc {}: {}",
+                        exception,
+                        pc,
+                        exception.details(Word::from(word))
+                    );
+                    state.program_counter = pc;
+                    dump(state);
+                    break;
+                }
+            },
         }
 
         // r0 must always be 0. restore it if a rogue instruction modified it
         state.registers[0] = 0;
-        println!("{}", state);
+        dump(state);
         state.num_executed_instructions += 1;
     }
 
     Ok(())
 }
 
-/// converts an i16 to i32, but inputs and outputs unsigned ints
-pub fn sign_extend(num: u16) -> u32 {
+/// Execute `word`, after the pc has moved past it. Gives whether it was `halt`, or the exception it caused without
+/// changing anything
+fn step(state: &mut State, word: u32, latency: usize) -> Result<bool, Exception> {
+    let instr = match Word::from(word) {
+        Word::Instr(instr) => instr,
+        Word::Data(bits, err) => return Err(Exception::Illegal(bits, err)),
+    };
+
+    match instr {
+        Instruction::Math { func, rs, rt, .. } if func.writes_hi_lo() => {
+            (state.hi, state.lo) =
+                func.eval_hi_lo(state.registers[rs as usize], state.registers[rt as usize]);
+            state.mul_div_busy += latency;
+        }
+        Instruction::Math {
+            func: MathFunc::MFHI,
+            rd,
+            ..
+        } => state.registers[rd as usize] = state.hi,
+        Instruction::Math {
+            func: MathFunc::MFLO,
+            rd,
+            ..
+        } => state.registers[rd as usize] = state.lo,
+        Instruction::Math {
+            func,
+            rd,
+            rs,
+            rt,
+            shamt,
+        } => {
+            let (rs, rt) = (state.registers[rs as usize], state.registers[rt as usize]);
+            if func.overflows(rs, rt) {
+                return Err(Exception::Overflow);
+            }
+            state.registers[rd as usize] = func.eval(rs, rt, shamt)
+        }
+
+        Instruction::Load { op, rt, rs, imm } => {
+            let addr = state.registers[rs as usize].wrapping_add(sign_extend(imm));
+            let value = mem::load(&state.memory, addr, op.width(), state.endian)
+                .map_err(Exception::AddressLoad)?;
+            state.registers[rt as usize] = op.extend_load(value);
+        }
+        Instruction::Store { op, rt, rs, imm } => {
+            let addr = state.registers[rs as usize].wrapping_add(sign_extend(imm));
+            let value = state.registers[rt as usize];
+            mem::store(&mut state.memory, addr, op.width(), value, state.endian)
+                .map_err(Exception::AddressStore)?;
+        }
+        Instruction::Imm { op, rt, rs, imm } => {
+            state.registers[rt as usize] = op.eval_imm(state.registers[rs as usize], imm)
+        }
+        Instruction::Branch { rs, rt, imm, .. } => {
+            if instr.taken(state.registers[rs as usize], state.registers[rt as usize]) {
+                state.program_counter =
+                    (state.program_counter as u32).wrapping_add(sign_extend(imm)) as usize;
+            }
+        }
+        Instruction::Jump { link, target } => {
+            if link {
+                state.registers[LINK_REGISTER as usize] = state.program_counter as u32;
+            }
+            // Within the 256MB of the next instruction
+            state.program_counter = state.program_counter & !0x0fff_ffff | (target as usize) << 2;
+        }
+        Instruction::Jalr { rd, rs } => {
+            // Read the target first, rd and rs may be the same register
+            let target = state.registers[rs as usize];
+            state.registers[rd as usize] = state.program_counter as u32;
+            state.program_counter = target as usize;
+        }
+        Instruction::Mfc0 { rt, rd } => state.registers[rt as usize] = state.cp0.read(rd),
+        Instruction::Syscall => return Err(Exception::Syscall),
+        Instruction::Break => return Err(Exception::Break),
+        Instruction::Halt => return Ok(true),
+    }
+    Ok(false)
+}
+
+/// converts an i16 to i32, but outputs an unsigned int
+pub fn sign_extend(num: i16) -> u32 {
     /* convert a 16 bit number into a 32-bit Sun number */
     // pads the i16 with zeroes. if negative, pads it with 1's instead
-    i32::from(num as i16) as u32
+    i32::from(num) as u32
 }
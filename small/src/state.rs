@@ -1,26 +1,39 @@
+use assembler::{cp0::Cp0, mem::Endian, reg};
 use std::{fmt::Display, io::BufRead};
 
 #[derive(Default)]
 pub struct State {
-    /// Memory *should* be byte addressable, but in the tests loads and stores are always word aligned
+    /// Loads and stores address its bytes, see [`assembler::mem`]
     pub memory: Vec<u32>,
+    pub endian: Endian,
     pub registers: [u32; 32],
     pub program_counter: usize,
     pub num_executed_instructions: usize,
+    /// Written by multiplies and divides, and read by `mfhi` and `mflo`
+    pub hi: u32,
+    pub lo: u32,
+    /// Cycles the multiply/divide unit would have spent working
+    pub mul_div_busy: usize,
+    pub cp0: Cp0,
 }
 
 impl State {
+    /// Read memory from a hex file, one word per line
     pub fn with_memory(memory: impl BufRead) -> Self {
         let memory = memory
             .lines()
-            .enumerate()
-            .map(|(idx, line)| {
+            .map(|line| {
                 let line = line.unwrap();
-                let bits: u32 = u32::from_str_radix(&line, 16).unwrap();
-                println!("memory[{}]={:08x}", idx, bits);
-                bits
+                u32::from_str_radix(&line, 16).unwrap()
             })
             .collect();
+        Self::with_words(memory)
+    }
+
+    pub fn with_words(memory: Vec<u32>) -> Self {
+        for (idx, bits) in memory.iter().enumerate() {
+            println!("memory[{}]={:08x}", idx, bits);
+        }
         println!();
         Self {
             memory,
@@ -41,7 +54,24 @@ impl Display for State {
 
         writeln!(f, "\tregisters:")?;
         for (key, val) in self.registers.iter().enumerate() {
-            writeln!(f, "\t\treg[{}] 0x{:x}\t({})", key, val, *val as i32)?;
+            // `{:#}` annotates registers with their conventional names
+            if f.alternate() {
+                write!(f, "\t\treg[{}] {}", key, reg::name(key as u8))?;
+            } else {
+                write!(f, "\t\treg[{}]", key)?;
+            }
+            writeln!(f, " 0x{:x}\t({})", val, *val as i32)?;
+        }
+        // Only once it's been used, so programs without multiplies look like they always have
+        if self.mul_div_busy > 0 {
+            writeln!(f, "\thi 0x{:x}\t({})", self.hi, self.hi as i32)?;
+            writeln!(f, "\tlo 0x{:x}\t({})", self.lo, self.lo as i32)?;
+        }
+        // Only once an exception has been taken
+        if self.cp0.taken > 0 {
+            writeln!(f, "\tepc={}", self.cp0.epc)?;
+            writeln!(f, "\tcause=0x{:x}", self.cp0.cause)?;
+            writeln!(f, "\tbadvaddr=0x{:x}", self.cp0.bad_vaddr)?;
         }
 
         Ok(())